@@ -0,0 +1,122 @@
+use std::process::{Child, Command as Process, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use http::Uri;
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+use tokio_util::codec::Decoder;
+use tokio_websockets::{ClientBuilder, MaybeTlsStream, Message, WebSocketStream};
+
+use rustdb::codec::RespCodec;
+use rustdb::command::Command;
+use rustdb::resp::{websocket_message, RESP};
+use rustdb::value::Value;
+
+const COMMANDS: usize = 10_000;
+
+type Connection = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// Kills the server when the benchmark is done
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start_server(port: u16) -> Server {
+    let process = Process::new(env!("CARGO_BIN_EXE_server"))
+        .args(["--port", &port.to_string(), "--no-autosave"])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    Server(process)
+}
+
+async fn connect(port: u16) -> Connection {
+    let uri = Uri::from_str(&format!("ws://127.0.0.1:{}", port)).unwrap();
+    for _ in 0..100 {
+        if let Ok((client, _)) = ClientBuilder::from_uri(uri.clone()).connect().await {
+            return client;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("server didn't start listening on port {}", port);
+}
+
+// The commands encoded into messages of `batch_size` commands each
+fn messages(batch_size: usize) -> Vec<Vec<u8>> {
+    let commands: Vec<Command> = (0..COMMANDS)
+        .map(|i| Command::Set(format!("key/{}", i), Value::Int(i as i64), None))
+        .collect();
+    commands
+        .chunks(batch_size)
+        .map(|batch| batch.iter().flat_map(|command| command.clone().encode_resp()).collect())
+        .collect()
+}
+
+async fn receive_replies(stream: &mut SplitStream<Connection>, count: usize) {
+    let mut codec = RespCodec::new();
+    let mut buffer = BytesMut::new();
+    let mut replies = 0;
+    while replies < count {
+        let msg = stream.next().await.unwrap().unwrap();
+        buffer.extend_from_slice(msg.as_payload());
+        while codec.decode(&mut buffer).unwrap().is_some() {
+            replies += 1;
+        }
+    }
+}
+
+// One command at a time, waiting for each reply before sending the next
+async fn one_at_a_time(sink: &mut SplitSink<Connection, Message>, stream: &mut SplitStream<Connection>, messages: &[Vec<u8>]) {
+    for message in messages {
+        sink.send(websocket_message(message.clone())).await.unwrap();
+        receive_replies(stream, 1).await;
+    }
+}
+
+// Every message sent back to back, with the replies read as they arrive
+async fn pipelined(sink: &mut SplitSink<Connection, Message>, stream: &mut SplitStream<Connection>, messages: &[Vec<u8>]) {
+    let send = async {
+        for message in messages {
+            sink.feed(websocket_message(message.clone())).await.unwrap();
+        }
+        sink.flush().await.unwrap();
+    };
+    tokio::join!(send, receive_replies(stream, COMMANDS));
+}
+
+fn bench_pipeline(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let _server = start_server(port);
+    let (mut sink, mut stream) = runtime.block_on(connect(port)).split();
+
+    let mut group = c.benchmark_group("pipeline_10k_sets");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(COMMANDS as u64));
+
+    let single = messages(1);
+    group.bench_function("unpipelined", |b| {
+        b.iter(|| runtime.block_on(one_at_a_time(&mut sink, &mut stream, &single)))
+    });
+    for batch_size in [1, 100, 1000] {
+        let messages = messages(batch_size);
+        group.bench_function(format!("pipelined/{}_per_message", batch_size), |b| {
+            b.iter(|| runtime.block_on(pipelined(&mut sink, &mut stream, &messages)))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_pipeline);
+criterion_main!(benches);
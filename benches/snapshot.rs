@@ -0,0 +1,84 @@
+use std::path::PathBuf;
+use std::time::Instant;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use rustdb::snapshot::Compression;
+use rustdb::table::Table;
+use rustdb::value::Value;
+
+const KEYS: usize = 1_000_000;
+
+const CODECS: [Compression; 3] = [Compression::None, Compression::Lz4, Compression::Zstd];
+
+fn fill() -> Table<String, Value> {
+    let mut table = Table::new();
+    for i in 0..KEYS {
+        let value = if i % 2 == 0 {
+            Value::Int(i as i64)
+        } else {
+            Value::String(format!("user:{}:session:{:x}", i, i * 7919))
+        };
+        table.set(format!("key/{}", i), value);
+    }
+    table
+}
+
+fn snapshot_path(compression: Compression) -> PathBuf {
+    std::env::temp_dir().join(format!("rustdb-bench-{:?}-{}", compression, std::process::id()))
+}
+
+// A single save and load of each codec, for a quick side-by-side comparison of file sizes
+fn report_sizes(table: &Table<String, Value>) {
+    println!("{} keys:", KEYS);
+    for compression in CODECS {
+        let path = snapshot_path(compression);
+        let path = path.to_str().unwrap();
+
+        let start = Instant::now();
+        table.to_disk_compressed(path, compression).unwrap();
+        let save = start.elapsed();
+
+        let start = Instant::now();
+        Table::<String, Value>::from_disk(path).unwrap();
+        let load = start.elapsed();
+
+        let size = std::fs::metadata(path).unwrap().len();
+        std::fs::remove_file(path).unwrap();
+        println!(
+            "  {:<5} size={:.1} MiB save={:.0} ms load={:.0} ms",
+            compression.to_string(),
+            size as f64 / (1024.0 * 1024.0),
+            save.as_secs_f64() * 1000.0,
+            load.as_secs_f64() * 1000.0,
+        );
+    }
+}
+
+fn bench_snapshot(c: &mut Criterion) {
+    let table = fill();
+    report_sizes(&table);
+
+    let mut group = c.benchmark_group("snapshot_1m");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(KEYS as u64));
+
+    for compression in CODECS {
+        let name = compression.to_string();
+        let path = snapshot_path(compression);
+        let path = path.to_str().unwrap();
+
+        group.bench_function(format!("save/{}", name), |b| {
+            b.iter(|| table.to_disk_compressed(path, compression).unwrap())
+        });
+        group.bench_function(format!("load/{}", name), |b| {
+            b.iter(|| Table::<String, Value>::from_disk(path).unwrap())
+        });
+        std::fs::remove_file(path).unwrap();
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_snapshot);
+criterion_main!(benches);
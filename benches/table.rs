@@ -0,0 +1,109 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use rustdb::table::{Table, TableConfig};
+use rustdb::value::Value;
+
+// Counts live heap bytes so we can report how much memory each configuration uses.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const KEYS: usize = 1_000_000;
+
+const CONFIGS: [(f64, usize); 6] = [
+    (0.5, 1),
+    (1.0, 1),
+    (2.0, 2),
+    (4.0, 4),
+    (4.0, 8),
+    (8.0, 8),
+];
+
+fn keys() -> Vec<(String, Value)> {
+    (0..KEYS)
+        .map(|i| (format!("key/{}", i), Value::Int(i as i64)))
+        .collect()
+}
+
+fn fill(config: TableConfig, keys: impl IntoIterator<Item = (String, Value)>) -> Table<String, Value> {
+    let mut table = Table::with_config(config);
+    for (key, value) in keys {
+        table.set(key, value);
+    }
+    table
+}
+
+fn report_memory() {
+    println!("{} keys:", KEYS);
+    for (load_factor, bucket_capacity) in CONFIGS {
+        let config = TableConfig {
+            load_factor,
+            bucket_capacity,
+        };
+
+        // Generate the keys lazily so that everything allocated belongs to the table
+        let before = ALLOCATED.load(Ordering::Relaxed);
+        let table = fill(
+            config,
+            (0..KEYS).map(|i| (format!("key/{}", i), Value::Int(i as i64))),
+        );
+        let used = ALLOCATED.load(Ordering::Relaxed) - before;
+
+        println!(
+            "  load_factor={:<4} bucket_capacity={:<2} buckets={:<8} memory={:.1} MiB ({:.1} bytes/key)",
+            load_factor,
+            bucket_capacity,
+            table.bucket_count(),
+            used as f64 / (1024.0 * 1024.0),
+            used as f64 / KEYS as f64,
+        );
+    }
+}
+
+fn bench_insert(c: &mut Criterion) {
+    report_memory();
+
+    let mut group = c.benchmark_group("insert_1m");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(KEYS as u64));
+
+    for (load_factor, bucket_capacity) in CONFIGS {
+        let config = TableConfig {
+            load_factor,
+            bucket_capacity,
+        };
+        group.bench_function(
+            format!("load_factor={}/bucket_capacity={}", load_factor, bucket_capacity),
+            |b| b.iter_batched(keys, |keys| fill(config, keys), BatchSize::PerIteration),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_insert);
+criterion_main!(benches);
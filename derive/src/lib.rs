@@ -0,0 +1,259 @@
+// Generates the RESP encoding and decoding of rustdb's `Command` enum from its definition, along with
+// the table of command names and usage strings that the client's parser and HELP text are built from.
+//
+// Each variant is annotated with `#[resp(...)]`:
+//
+//   usage = "GET <key>"   How the command is written, for HELP and usage errors. Required.
+//   name = "GET"          The command's name, if it isn't the variant's name in upper case.
+//   parse = path          A function taking the arguments (everything after the name) and returning
+//                         `Result<Self, Error>`, for commands with options that fields can't describe.
+//   encode = path         A function taking the variant's fields and returning the arguments, to go with `parse`.
+//   client_only           Handled by the client itself, so never decoded from a request.
+//
+// Otherwise each field is one argument, decoded and encoded with rustdb's `command::Arg` trait. `Option`
+// fields are optional arguments, and have to come after the rest.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, Data, DeriveInput, Fields, GenericArgument, LitStr, Path, PathArguments, Type, Variant,
+};
+
+struct Argument {
+    ty: Type,
+    optional: bool,
+}
+
+struct CommandVariant {
+    variant: Variant,
+    name: String,
+    usage: String,
+    client_only: bool,
+    parse: Option<Path>,
+    encode: Option<Path>,
+    arguments: Vec<Argument>,
+}
+
+#[proc_macro_derive(RespCommand, attributes(resp))]
+pub fn derive_resp_command(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new_spanned(&input.ident, "RespCommand can only be derived for enums"));
+    };
+    let commands = data.variants.iter().map(parse_variant).collect::<syn::Result<Vec<_>>>()?;
+
+    let ident = &input.ident;
+    let names = commands.iter().map(|command| &command.name);
+    let usages = commands.iter().map(|command| &command.usage);
+    let usage_arms = commands.iter().map(|command| {
+        let (name, usage) = (&command.name, &command.usage);
+        quote! { #name => ::std::option::Option::Some(#usage), }
+    });
+    let encode_arms = commands.iter().map(encode_arm);
+    let decode_arms = commands.iter().filter(|command| !command.client_only).map(decode_arm);
+
+    Ok(quote! {
+        impl #ident {
+            // Every command's name, in the order they are declared
+            pub const NAMES: &'static [&'static str] = &[#(#names),*];
+
+            // How each command is written, in the same order
+            pub const USAGE: &'static [&'static str] = &[#(#usages),*];
+
+            pub fn usage(name: &str) -> ::std::option::Option<&'static str> {
+                match name.to_uppercase().as_str() {
+                    #(#usage_arms)*
+                    _ => ::std::option::Option::None,
+                }
+            }
+
+            // Interprets an already-decoded RESP value as a command
+            pub fn from_value(value: ::rustdb::value::Value) -> ::std::result::Result<Self, ::rustdb::Error> {
+                use ::rustdb::command::Arg;
+                use ::rustdb::value::Value;
+                use ::rustdb::Error;
+
+                let Value::Array(a) = value else {
+                    return Err(Error::Protocol("expected array of strings".to_string()));
+                };
+                let Some((Value::String(cmd), args)) = a.split_first() else {
+                    return Err(Error::Protocol("expected array of strings".to_string()));
+                };
+
+                // Command names are case-insensitive, as in Redis
+                match cmd.to_uppercase().as_str() {
+                    #(#decode_arms)*
+                    _ => Err(Error::UnknownCommand(cmd.clone())),
+                }
+            }
+        }
+
+        impl ::rustdb::resp::RESP for #ident {
+            fn encode_resp(self) -> ::std::vec::Vec<u8> {
+                use ::rustdb::command::Arg;
+                use ::rustdb::value::Value;
+
+                let args: ::std::vec::Vec<Value> = match self {
+                    #(#encode_arms)*
+                };
+                ::rustdb::resp::RESP::encode_resp(Value::Array(args))
+            }
+
+            fn decode_resp(bytes: &[u8]) -> ::std::result::Result<Self, ::rustdb::Error> {
+                Self::from_value(<::rustdb::value::Value as ::rustdb::resp::RESP>::decode_resp(bytes)?)
+            }
+        }
+    })
+}
+
+fn parse_variant(variant: &Variant) -> syn::Result<CommandVariant> {
+    let mut name = None;
+    let mut usage = None;
+    let mut client_only = false;
+    let mut parse = None;
+    let mut encode = None;
+    for attr in variant.attrs.iter().filter(|attr| attr.path().is_ident("resp")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                name = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("usage") {
+                usage = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("client_only") {
+                client_only = true;
+            } else if meta.path.is_ident("parse") {
+                parse = Some(meta.value()?.parse::<Path>()?);
+            } else if meta.path.is_ident("encode") {
+                encode = Some(meta.value()?.parse::<Path>()?);
+            } else {
+                return Err(meta.error("unknown resp attribute"));
+            }
+            Ok(())
+        })?;
+    }
+
+    let Some(usage) = usage else {
+        return Err(syn::Error::new_spanned(&variant.ident, "missing #[resp(usage = \"...\")]"));
+    };
+    if parse.is_some() != encode.is_some() {
+        return Err(syn::Error::new_spanned(&variant.ident, "`parse` and `encode` have to be given together"));
+    }
+
+    let fields = match &variant.fields {
+        Fields::Unnamed(fields) => fields.unnamed.iter().collect(),
+        Fields::Unit => vec![],
+        Fields::Named(_) => {
+            return Err(syn::Error::new_spanned(&variant.ident, "command variants must be tuple or unit variants"));
+        }
+    };
+    let arguments = fields
+        .into_iter()
+        .map(|field| match option_inner(&field.ty) {
+            Some(ty) => Argument { ty: ty.clone(), optional: true },
+            None => Argument { ty: field.ty.clone(), optional: false },
+        })
+        .collect::<Vec<_>>();
+    if parse.is_none() && arguments.windows(2).any(|pair| pair[0].optional && !pair[1].optional) {
+        return Err(syn::Error::new_spanned(&variant.ident, "optional arguments have to come last"));
+    }
+
+    Ok(CommandVariant {
+        name: name.unwrap_or_else(|| variant.ident.to_string().to_uppercase()),
+        variant: variant.clone(),
+        usage,
+        client_only,
+        parse,
+        encode,
+        arguments,
+    })
+}
+
+// The `T` in `Option<T>`
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    match args.args.first()? {
+        GenericArgument::Type(inner) => Some(inner),
+        _ => None,
+    }
+}
+
+// The match arm turning a variant into its arguments, starting with its name
+fn encode_arm(command: &CommandVariant) -> TokenStream2 {
+    let variant = &command.variant.ident;
+    let name = &command.name;
+    let bindings = (0..command.arguments.len()).map(|i| format_ident!("arg{}", i)).collect::<Vec<_>>();
+    let pattern = if matches!(command.variant.fields, Fields::Unit) {
+        quote! { Self::#variant }
+    } else {
+        quote! { Self::#variant(#(#bindings),*) }
+    };
+
+    let push_arguments = match &command.encode {
+        Some(encode) => quote! { args.extend(#encode(#(#bindings),*)); },
+        None => {
+            let pushes = command.arguments.iter().zip(&bindings).map(|(argument, binding)| {
+                if argument.optional {
+                    quote! { args.extend(#binding.map(Arg::into_arg)); }
+                } else {
+                    quote! { args.push(Arg::into_arg(#binding)); }
+                }
+            });
+            quote! { #(#pushes)* }
+        }
+    };
+
+    quote! {
+        #pattern => {
+            let mut args = vec![Value::String(#name.to_string())];
+            #push_arguments
+            args
+        }
+    }
+}
+
+// The match arm turning a command's arguments into a variant, checking that there are the right number
+fn decode_arm(command: &CommandVariant) -> TokenStream2 {
+    let variant = &command.variant.ident;
+    let name = &command.name;
+    if let Some(parse) = &command.parse {
+        return quote! { #name => #parse(args), };
+    }
+    if command.arguments.is_empty() {
+        return quote! {
+            #name if !args.is_empty() => Err(Error::WrongArity(#name.to_string())),
+            #name => Ok(Self::#variant),
+        };
+    }
+
+    let required = command.arguments.iter().filter(|argument| !argument.optional).count();
+    let total = command.arguments.len();
+    let fields = command.arguments.iter().enumerate().map(|(i, argument)| {
+        let ty = &argument.ty;
+        if argument.optional {
+            quote! { args.get(#i).map(<#ty as Arg>::from_arg).transpose()? }
+        } else {
+            quote! { <#ty as Arg>::from_arg(&args[#i])? }
+        }
+    });
+    quote! {
+        #name => {
+            if args.len() < #required || args.len() > #total {
+                return Err(Error::WrongArity(#name.to_string()));
+            }
+            Ok(Self::#variant(#(#fields),*))
+        }
+    }
+}
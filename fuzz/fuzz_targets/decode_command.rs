@@ -0,0 +1,24 @@
+#![no_main]
+
+// Anything a client sends should decode to a command or an error, never a panic
+
+use libfuzzer_sys::fuzz_target;
+use rustdb::command::Command;
+use rustdb::resp::RESP;
+use rustdb::value::{parse_value, Value};
+
+fuzz_target!(|data: &[u8]| {
+    match Command::decode_resp(data) {
+        Ok(command) => {
+            // Whatever was accepted has to survive being written to the append-only file and read back
+            let encoded = command.clone().encode_resp();
+            assert!(Command::decode_resp(&encoded).is_ok());
+        }
+        Err(e) => {
+            // The error reply has to be exactly one value, whatever of the input it quotes
+            let reply = Value::from(e).encode_resp();
+            let (rest, _) = parse_value(&reply).unwrap();
+            assert!(rest.is_empty());
+        }
+    }
+});
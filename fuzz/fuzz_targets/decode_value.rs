@@ -0,0 +1,24 @@
+#![no_main]
+
+// Any input should decode to a value or an error, never a panic, and so should the streaming decoder
+// the server reads connections with
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use rustdb::codec::RespCodec;
+use rustdb::resp::{Protocol, RESP};
+use rustdb::value::Value;
+use tokio_util::codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(value) = Value::decode_resp(data) {
+        let _ = value.to_string();
+        let _ = value.clone().encode(Protocol::Resp3);
+        let _ = value.encode(Protocol::Resp2);
+    }
+
+    let mut codec = RespCodec::new();
+    codec.inline = true;
+    let mut buffer = BytesMut::from(data);
+    while let Ok(Some(_)) = codec.decode(&mut buffer) {}
+});
@@ -0,0 +1,196 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::command::Command;
+use crate::resp::{is_incomplete, RESP};
+use crate::snapshot::sync_parent_dir;
+use crate::value::parse_value;
+
+// When to flush the append-only file to disk. Modelled on Redis's appendfsync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    // After every write. Slow, but nothing that was acknowledged can be lost.
+    Always,
+    // Once per second (the caller is responsible for calling `sync` on a timer).
+    EverySec,
+    // Never, leaving it up to the operating system.
+    No,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(Self::Always),
+            "everysec" => Ok(Self::EverySec),
+            "no" => Ok(Self::No),
+            _ => Err(format!("unknown fsync policy: {}", s)),
+        }
+    }
+}
+
+// A log of every command that modified the database, stored as a sequence of RESP frames.
+// Replaying the log from the start rebuilds the database.
+pub struct AppendOnlyFile {
+    path: PathBuf,
+    file: File,
+    fsync: FsyncPolicy,
+
+    // Whether anything has been written since the last fsync
+    dirty: bool,
+
+    // While a rewrite is in progress, new commands are also collected here so that they can be
+    // added to the end of the rewritten log before it replaces the current one.
+    rewrite_buffer: Option<Vec<u8>>,
+}
+
+fn encode(commands: &[Command]) -> Vec<u8> {
+    commands
+        .iter()
+        .flat_map(|command| command.clone().encode_resp())
+        .collect()
+}
+
+impl AppendOnlyFile {
+    pub fn open(path: &Path, fsync: FsyncPolicy) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            fsync,
+            dirty: false,
+            rewrite_buffer: None,
+        })
+    }
+
+    // If this fails, whatever part of the commands was written is cut off again, so that the log doesn't
+    // end in a partial command that later ones would be appended after.
+    pub fn append(&mut self, commands: &[Command]) -> io::Result<()> {
+        let bytes = encode(commands);
+        let len = self.file.metadata()?.len();
+        if let Err(e) = self.file.write_all(&bytes) {
+            let _ = self.file.set_len(len);
+            return Err(e);
+        }
+        if let Some(buffer) = &mut self.rewrite_buffer {
+            buffer.extend_from_slice(&bytes);
+        }
+
+        if self.fsync == FsyncPolicy::Always {
+            self.file.sync_data()?;
+        } else {
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    // Flushes any writes since the last call to disk
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.dirty {
+            self.file.sync_data()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_rewriting(&self) -> bool {
+        self.rewrite_buffer.is_some()
+    }
+
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.file.metadata()?.len() == 0)
+    }
+
+    // Reads every command in a log, along with the length of the part of it they take up. A truncated
+    // command at the very end (e.g. from a crash in the middle of a write) is skipped with a warning,
+    // but anything else that can't be parsed is an error.
+    pub fn load(path: &Path) -> Result<(Vec<Command>, u64), String> {
+        let contents = fs::read(path).map_err(|e| e.to_string())?;
+
+        let mut commands = Vec::new();
+        let mut remaining = contents.as_slice();
+        while !remaining.is_empty() {
+            match parse_value(remaining) {
+                Ok((rest, value)) => {
+                    let command = Command::from_value(value).map_err(|e| format!("invalid append-only file: {}", e))?;
+                    commands.push(command);
+                    // Logs written before arrays were encoded to spec have an extra CRLF after each command
+                    remaining = rest.strip_prefix(b"\r\n").unwrap_or(rest);
+                }
+                Err(nom::Err::Error(e)) if is_incomplete(&e) => {
+                    println!(
+                        "Warning: ignoring a truncated command at the end of {}",
+                        path.display()
+                    );
+                    break;
+                }
+                Err(e) => {
+                    let e = e.map_input(|input| String::from_utf8_lossy(input).into_owned());
+                    return Err(format!("invalid append-only file: {}", e));
+                }
+            }
+        }
+        Ok((commands, (contents.len() - remaining.len()) as u64))
+    }
+
+    // Cuts the log off after `len` bytes, i.e. drops the truncated command that `load` skipped, if any.
+    // Otherwise new commands would be appended after it and the log couldn't be loaded again.
+    pub fn truncate(&mut self, len: u64) -> io::Result<()> {
+        if self.file.metadata()?.len() > len {
+            self.file.set_len(len)?;
+            self.file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // Writes a fresh log containing the given commands next to the current one. This is the slow
+    // part of a rewrite, so it doesn't need access to the open log and can run on another thread.
+    pub fn write_rewrite(path: &Path, commands: &[Command]) -> io::Result<()> {
+        let mut file = File::create(rewrite_path(path))?;
+        file.write_all(&encode(commands))?;
+        file.sync_all()
+    }
+
+    // Starts collecting new commands for a rewrite. Returns false if a rewrite is already in progress.
+    pub fn start_rewrite(&mut self) -> bool {
+        if self.rewrite_buffer.is_some() {
+            return false;
+        }
+        self.rewrite_buffer = Some(Vec::new());
+        true
+    }
+
+    // Adds the commands that arrived during the rewrite to the rewritten log, then swaps it in.
+    // The rewritten log is kept open through the rename rather than reopened afterwards, so that
+    // there's never a moment where new commands would go to the old, unlinked file.
+    pub fn finish_rewrite(&mut self) -> io::Result<()> {
+        let buffer = self.rewrite_buffer.take().unwrap_or_default();
+        let rewritten = rewrite_path(&self.path);
+
+        let mut file = OpenOptions::new().append(true).open(&rewritten)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+        fs::rename(&rewritten, &self.path)?;
+        self.file = file;
+        self.dirty = false;
+        sync_parent_dir(&self.path)
+    }
+
+    pub fn abort_rewrite(&mut self) {
+        self.rewrite_buffer = None;
+        let _ = fs::remove_file(rewrite_path(&self.path));
+    }
+}
+
+fn rewrite_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".rewrite");
+    PathBuf::from(name)
+}
@@ -0,0 +1,175 @@
+use clap::Parser;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process;
+
+use rustdb::hash::SipBuildHasher;
+use rustdb::snapshot::{read_payload, salvage_payload, Compression, Header};
+use rustdb::table::Table;
+use rustdb::value::Value;
+
+#[derive(Parser)]
+#[command(name = "rustdb-check")]
+#[command(version = "0.1.0")]
+#[command(about = "Inspects a rustdb snapshot, and recovers what it can from a damaged one", long_about = None)]
+struct Cli {
+    /// The snapshot file to check
+    path: String,
+    /// Write every entry that can be read to a new snapshot at this path
+    #[arg(long)]
+    recover: Option<String>,
+    /// How to compress the recovered snapshot: none, lz4 or zstd
+    #[arg(long, default_value = "none")]
+    compression: Compression,
+}
+
+// The same kind of table as the server's keyspace
+type Keyspace = Table<String, Value, SipBuildHasher>;
+
+// The longest bar in the bucket length histogram
+const HISTOGRAM_WIDTH: usize = 40;
+// Only the first few problems are printed, since a broken table can have one for every entry
+const MAX_PROBLEMS_SHOWN: usize = 20;
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "string",
+        Value::Int(_) => "int",
+        Value::Array(_) => "array",
+        Value::Null => "null",
+        Value::SimpleString(_) => "simple string",
+        Value::SimpleError(_) => "simple error",
+        Value::Bytes(_) => "bytes",
+        Value::Map(_) => "map",
+        Value::Set(_) => "set",
+        Value::Double(_) => "double",
+        Value::Boolean(_) => "boolean",
+        Value::BigNumber(_) => "big number",
+        Value::VerbatimString(..) => "verbatim string",
+        Value::Push(_) => "push",
+    }
+}
+
+fn print_header(header: Option<Header>) {
+    match header {
+        Some(header) => println!(
+            "Format: version {}, compression {}, created at {} (Unix ms), {} byte payload",
+            header.version, header.compression, header.created_at, header.payload_len
+        ),
+        None => println!("Format: headerless (from before snapshots were versioned)"),
+    }
+}
+
+fn print_stats(table: &Keyspace) {
+    // Counted from the same entries as the value types, which leave out expired ones
+    let mut types = BTreeMap::new();
+    for (_, value) in table.iter() {
+        *types.entry(type_name(value)).or_insert(0usize) += 1;
+    }
+    let keys = types.values().sum::<usize>();
+    println!("Keys: {}", keys);
+    if table.len() > keys {
+        println!("Expired keys not yet removed: {}", table.len() - keys);
+    }
+    println!(
+        "Buckets: {} (level {}, next split {}, load factor {:.2})",
+        table.bucket_count(),
+        table.current_level(),
+        table.next_split(),
+        table.load_factor()
+    );
+
+    let mut histogram = BTreeMap::new();
+    for len in table.bucket_lens() {
+        *histogram.entry(len).or_insert(0usize) += 1;
+    }
+    let most = histogram.values().copied().max().unwrap_or(0).max(1);
+    println!("Bucket lengths:");
+    for (len, count) in histogram {
+        let bar = "#".repeat((count * HISTOGRAM_WIDTH).div_ceil(most));
+        println!("  {:>4}: {:>9} {}", len, count, bar);
+    }
+
+    println!("Value types:");
+    for (name, count) in types {
+        println!("  {:<13} {:>9}", name, count);
+    }
+}
+
+// Returns whether the table is consistent
+fn print_problems(table: &Keyspace) -> bool {
+    let problems = table.verify();
+    if problems.is_empty() {
+        println!("Placement: every entry is in the right bucket");
+        return true;
+    }
+
+    println!("Found {} problems:", problems.len());
+    for problem in problems.iter().take(MAX_PROBLEMS_SHOWN) {
+        println!("  {}", problem);
+    }
+    if problems.len() > MAX_PROBLEMS_SHOWN {
+        println!("  ... and {} more", problems.len() - MAX_PROBLEMS_SHOWN);
+    }
+    false
+}
+
+// Rebuilds a table from whatever entries can be read, so that they all end up in the right buckets
+fn recover(path: &Path, output: &str, compression: Compression) -> Result<(), String> {
+    let (header, payload) = salvage_payload(path).map_err(|e| e.to_string())?;
+    if payload.is_empty() {
+        return Err("nothing could be read from the snapshot's payload".to_string());
+    }
+    let (entries, error) = Keyspace::recover_from_payload(&payload, header.is_none());
+    if let Some(error) = error {
+        println!("Recovery stopped early: {}", error);
+    }
+
+    let mut table = Keyspace::with_hasher(SipBuildHasher::random());
+    let total = entries.len();
+    for entry in entries {
+        table.set_with_expiry(entry.key().clone(), entry.value().clone(), entry.expires_at());
+    }
+    table
+        .to_disk_compressed(output, compression)
+        .map_err(|e| format!("failed to save {}: {}", output, e))?;
+    // Expired and duplicate entries don't make it into the table
+    println!("Recovered {} of {} entries into {}", table.len(), total, output);
+    Ok(())
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let path = Path::new(&cli.path);
+
+    let mut ok = match read_payload(path) {
+        Ok((header, payload)) => {
+            print_header(header);
+            match Keyspace::from_payload(&payload, header.is_none(), SipBuildHasher::random()) {
+                Ok(table) => {
+                    print_stats(&table);
+                    print_problems(&table)
+                }
+                Err(e) => {
+                    println!("Error: failed to decode the table: {}", e);
+                    false
+                }
+            }
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            false
+        }
+    };
+
+    if let Some(output) = &cli.recover {
+        if let Err(e) = recover(path, output, cli.compression) {
+            println!("Error: {}", e);
+            ok = false;
+        }
+    }
+
+    if !ok {
+        process::exit(1);
+    }
+}
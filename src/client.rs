@@ -1,24 +1,28 @@
-use clap::{command, Parser};
+use clap::Parser;
 use futures_util::{SinkExt, StreamExt};
 use http::Uri;
 use std::io;
 use std::io::Write;
 use std::str::FromStr;
-use tokio;
-use tokio_websockets::{ClientBuilder, Error, Message};
+use bytes::BytesMut;
+use tokio::net::TcpStream;
+use tokio_util::codec::Decoder;
+use tokio_websockets::{ClientBuilder, MaybeTlsStream, WebSocketStream};
 
 use nom::branch::alt;
-use nom::bytes::complete::{tag, tag_no_case, take_till1};
+use nom::bytes::complete::{tag, take_till1, take_while_m_n};
 use nom::character::complete::{anychar, i64, multispace0};
-use nom::combinator::value;
+use nom::combinator::{map, map_res, value, verify};
 use nom::multi::{many0, many_till, separated_list0};
-use nom::sequence::{delimited, tuple};
+use nom::sequence::{delimited, preceded, tuple};
 use nom::IResult;
 
 
+use rustdb::codec::RespCodec;
 use rustdb::command::Command;
-use rustdb::resp::RESP;
-use rustdb::value::Value;
+use rustdb::error::Error;
+use rustdb::resp::{websocket_message, RESP};
+use rustdb::value::{bulk_value, Value};
 
 #[derive(Parser)]
 #[command(name = "rustdb-client")]
@@ -28,6 +32,12 @@ use rustdb::value::Value;
 struct Cli {
     #[arg(long, default_value = "ws://127.0.0.1:3000")]
     uri: String,
+    /// Read commands from stdin, one per line, and send them without waiting for each reply (like redis-cli --pipe)
+    #[arg(long)]
+    pipe: bool,
+    /// How many commands to send in each message with --pipe
+    #[arg(long, default_value_t = 1000)]
+    batch_size: usize,
 }
 
 // Logic for parsing commands.
@@ -50,9 +60,26 @@ fn parse_readable_array(input: &str) -> IResult<&str, Value> {
     Ok((remaining, Value::Array(a)))
 }
 
+// A single character of a quoted string, with the same escapes that redis-cli understands
+// (\n, \r, \t, \", \\ and \xNN for arbitrary bytes)
+fn parse_literal_char(input: &str) -> IResult<&str, Vec<u8>> {
+    let hex_byte = map_res(take_while_m_n(2, 2, |c: char| c.is_ascii_hexdigit()), |hex| {
+        u8::from_str_radix(hex, 16)
+    });
+    alt((
+        map(preceded(tag("\\x"), hex_byte), |byte| vec![byte]),
+        value(b"\n".to_vec(), tag("\\n")),
+        value(b"\r".to_vec(), tag("\\r")),
+        value(b"\t".to_vec(), tag("\\t")),
+        value(b"\"".to_vec(), tag("\\\"")),
+        value(b"\\".to_vec(), tag("\\\\")),
+        map(anychar, |c| c.to_string().into_bytes()),
+    ))(input)
+}
+
 fn parse_literal_string(input: &str) -> IResult<&str, Value> {
-    let (remaining, (_, (s, _))) = tuple((tag("\""), many_till(anychar, tag("\""))))(input)?;
-    Ok((remaining, Value::String(s.iter().collect())))
+    let (remaining, (_, (chars, _))) = tuple((tag("\""), many_till(parse_literal_char, tag("\""))))(input)?;
+    Ok((remaining, bulk_value(chars.concat())))
 }
 
 fn parse_unquoted_string(input: &str) -> IResult<&str, Value> {
@@ -76,98 +103,106 @@ fn parse_readable_value(input: &str) -> IResult<&str, Value> {
     )(input)
 }
 
+// Any command in `Command`'s table, upper-cased
 fn parse_command_name(input: &str) -> IResult<&str, String> {
-    alt((
-        value("GET".to_string(), tag_no_case("GET")),
-        value("SET".to_string(), tag_no_case("SET")),
-        value("INC".to_string(), tag_no_case("INC")),
-        value("DEC".to_string(), tag_no_case("DEC")),
-        value("SAVE".to_string(), tag_no_case("SAVE")),
-        value("HELLO".to_string(), tag_no_case("HELLO")),
-        value("EXIT".to_string(), tag_no_case("EXIT")),
-        value("HELP".to_string(), tag_no_case("HELP")),
-    ))(input)
+    let known = |name: &str| Command::NAMES.iter().any(|known| known.eq_ignore_ascii_case(name));
+    let (remaining, name) = verify(take_till1(|c: char| c.is_whitespace()), known)(input)?;
+    Ok((remaining, name.to_uppercase()))
 }
 
-pub fn parse_readable_command(input: &str) -> Result<Command, String> {
-    if let Ok((remaining, (cmd, args))) = tuple((
+pub fn parse_readable_command(input: &str) -> Result<Command, Error> {
+    let Ok(("", (cmd, args))) = tuple((
         delimited(multispace0, parse_command_name, multispace0),
         many0(parse_readable_value),
-    ))(input)
-    {
-        assert!(remaining.is_empty());
-        match cmd.as_str() {
-            "GET" => {
-                if let [Value::String(key)] = args.as_slice() {
-                    Ok(Command::Get(key.clone()))
-                } else {
-                    Err("Invalid usage: GET <key>".to_string())
-                }
-            }
-            "SET" => {
-                if let [Value::String(key), value] = args.as_slice() {
-                    Ok(Command::Set(key.clone(), value.clone()))
-                } else {
-                    Err("Invalid usage: SET <key> <value>".to_string())
-                }
-            }
-            "INC" => {
-                if let [Value::String(key)] = args.as_slice() {
-                    Ok(Command::Inc(key.clone()))
-                } else {
-                    Err("Invalid usage: INC <key>".to_string())
-                }
-            }
-            "DEC" => {
-                if let [Value::String(key)] = args.as_slice() {
-                    Ok(Command::Dec(key.clone()))
-                } else {
-                    Err("Invalid usage: DEC <key>".to_string())
-                }
-            }
-            "SAVE" => {
-                if args.is_empty() {
-                    Ok(Command::Save)
-                } else {
-                    Err("Invalid usage: SAVE".to_string())
-                }
-            }
-            "HELLO" => {
-                if args.is_empty() {
-                    Ok(Command::Hello)
-                } else {
-                    Err("Invalid usage: HELLO".to_string())
-                }
-            }
-            "EXIT" => {
-                if args.is_empty() {
-                    Ok(Command::Exit)
-                } else {
-                    Err("Invalid usage: EXIT".to_string())
-                }
-            }
-            "HELP" => {
-                if args.is_empty() {
-                    Ok(Command::Help)
-                } else {
-                    Err("Invalid usage: HELP".to_string())
+    ))(input) else {
+        let name = input.split_whitespace().next().unwrap_or_default();
+        return Err(Error::UnknownCommand(name.to_string()));
+    };
+    let usage = || Error::Syntax(Command::usage(&cmd).unwrap_or_default().to_string());
+
+    // These never go to the server, so they aren't decoded like the rest
+    match cmd.as_str() {
+        "HELP" | "EXIT" if !args.is_empty() => return Err(usage()),
+        "HELP" => return Ok(Command::Help),
+        "EXIT" => return Ok(Command::Exit),
+        _ => {}
+    }
+
+    // The usage is more helpful than being told that the number of arguments is wrong
+    let request = Value::Array([vec![Value::String(cmd.clone())], args].concat());
+    Command::from_value(request).map_err(|e| match e {
+        Error::WrongArity(_) => usage(),
+        e => e,
+    })
+}
+
+
+// Sends every command from stdin in batches, while reading the replies as they come back, and reports
+// how many there were and how many were errors. Sending and receiving at the same time means the
+// server never has to wait for us to read its replies before it can read more commands.
+async fn pipe(client: WebSocketStream<MaybeTlsStream<TcpStream>>, batch_size: usize) -> Result<(), tokio_websockets::Error> {
+    let mut commands = Vec::new();
+    for (i, line) in io::stdin().lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_readable_command(&line) {
+            Ok(Command::Help | Command::Exit) => eprintln!("Error on line {}: not a server command", i + 1),
+            Ok(command) => commands.push(command),
+            Err(e) => eprintln!("Error on line {}: {}", i + 1, e),
+        }
+    }
+
+    let (mut sink, mut stream) = client.split();
+    let send = async {
+        for batch in commands.chunks(batch_size.max(1)) {
+            let frames = batch.iter().flat_map(|command| command.clone().encode_resp()).collect();
+            sink.send(websocket_message(frames)).await?;
+        }
+        Ok::<_, tokio_websockets::Error>(())
+    };
+    let receive = async {
+        let mut codec = RespCodec::new();
+        let mut buffer = BytesMut::new();
+        let (mut replies, mut errors) = (0, 0);
+        while replies < commands.len() {
+            let Some(msg) = stream.next().await else {
+                break;
+            };
+            let msg = msg?;
+            buffer.extend_from_slice(msg.as_payload());
+            let mut decode = || codec.decode(&mut buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+            while let Some(reply) = decode()? {
+                if let Value::SimpleError(e) = &reply {
+                    println!("Error: {}", e);
+                    errors += 1;
                 }
+                replies += 1;
             }
-            _ => Err(format!("Unknown command: {}", cmd)),
         }
-    } else {
-        Err("Invalid command".to_string())
-    }
+        Ok::<_, tokio_websockets::Error>((replies, errors))
+    };
+    let (sent, received) = tokio::join!(send, receive);
+    sent?;
+    let (replies, errors) = received?;
+    println!("All data transferred. errors: {}, replies: {}", errors, replies);
+
+    sink.reunite(stream).unwrap().close().await?;
+    Ok(())
 }
 
-
 #[tokio::main]
-pub async fn main() -> Result<(), Error> {
+pub async fn main() -> Result<(), tokio_websockets::Error> {
     let cli = Cli::parse();
 
     let uri = Uri::from_str(cli.uri.as_str()).unwrap();
     let (mut client, _) = ClientBuilder::from_uri(uri).connect().await?;
 
+    if cli.pipe {
+        return pipe(client, cli.batch_size).await;
+    }
+
     loop {
         let mut input = String::new();
         print!("> ");
@@ -184,13 +219,9 @@ pub async fn main() -> Result<(), Error> {
         match parse_readable_command(input.as_str()) {
             Ok(Command::Help) => {
                 println!("Available commands:");
-                println!("  GET <key>");
-                println!("  SET <key> <value>");
-                println!("  INC <key>");
-                println!("  DEC <key>");
-                println!("  SAVE");
-                println!("  EXIT");
-                println!("  HELP");
+                for usage in Command::USAGE {
+                    println!("  {}", usage);
+                }
             }
             Ok(Command::Exit) => {
                 println!("Exiting...");
@@ -198,13 +229,15 @@ pub async fn main() -> Result<(), Error> {
             }
             Ok(command) => {
                 client
-                    .send(Message::text(Command::encode_resp(command)))
+                    .send(websocket_message(Command::encode_resp(command)))
                     .await?;
 
                 while let Some(Ok(msg)) = client.next().await {
-                    if let Some(text) = msg.as_text() {
-                        let response = Value::decode_resp(text.to_string()).unwrap();
-                        println!("{}", response.string_repr());
+                    if msg.is_text() || msg.is_binary() {
+                        match Value::decode_resp(msg.as_payload()) {
+                            Ok(response) => println!("{}", response.string_repr()),
+                            Err(e) => println!("Error: couldn't read the reply: {}", e),
+                        }
                         break;
                     }
                 }
@@ -0,0 +1,215 @@
+use bytes::{Buf, BytesMut};
+use nom::error::ErrorKind;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::error::Error;
+use crate::resp::{is_incomplete, Protocol, MAX_AGGREGATE_LEN, MAX_BULK_LEN, MAX_NESTING};
+use crate::value::{parse_inline, parse_value, Value};
+
+// How much input can be buffered waiting for a frame to complete, like Redis's client-query-buffer-limit
+pub const DEFAULT_MAX_FRAME_LEN: usize = 1024 * 1024 * 1024;
+
+// `parse_value` treats running out of input like any other error. This turns those errors into
+// `Incomplete` instead, so that something reading from a stream knows to wait for more.
+pub fn parse_value_streaming(input: &[u8]) -> nom::IResult<&[u8], Value> {
+    streaming(parse_value(input))
+}
+
+// The same for a request to the server, which can also be an inline command. Like Redis, anything
+// that doesn't start with '*' is taken to be one.
+pub fn parse_request_streaming(input: &[u8]) -> nom::IResult<&[u8], Value> {
+    match input.first() {
+        Some(b'*') => parse_value_streaming(input),
+        _ => streaming(parse_inline(input)),
+    }
+}
+
+fn streaming(result: nom::IResult<&[u8], Value>) -> nom::IResult<&[u8], Value> {
+    match result {
+        Err(nom::Err::Error(e)) if is_incomplete(&e) => Err(nom::Err::Incomplete(nom::Needed::Unknown)),
+        result => result,
+    }
+}
+
+// How far an incomplete frame has been checked, so that the next call can carry on from there instead of
+// parsing it all again. Otherwise a big array arriving a little at a time would take quadratic time.
+#[derive(Debug, Clone, Default)]
+struct Progress {
+    // The length of the elements checked so far
+    offset: usize,
+    // How many more elements each aggregate that has been started needs, innermost last
+    pending: Vec<u64>,
+    // How much of the next element has been searched for the end of its first line
+    searched: usize,
+}
+
+enum Scan {
+    Complete,
+    Incomplete,
+    // Something the scan doesn't understand, which is left to the parser to report
+    Invalid,
+}
+
+// The length in a line like "$5" or "*-1"
+fn declared_len(line: &[u8]) -> Option<i64> {
+    std::str::from_utf8(line).ok()?.parse().ok()
+}
+
+// Works out whether the buffer holds a whole frame by walking its elements' headers, without building
+// any values. Picks up where the last call left off.
+fn scan(input: &[u8], progress: &mut Progress) -> Scan {
+    loop {
+        let rest = &input[progress.offset..];
+        let Some(&kind) = rest.first() else {
+            return Scan::Incomplete;
+        };
+        // The last byte searched could have been a CR whose LF hadn't arrived yet
+        let from = progress.searched.saturating_sub(1);
+        let Some(line_len) = rest[from..].windows(2).position(|pair| pair == b"\r\n") else {
+            progress.searched = rest.len();
+            return Scan::Incomplete;
+        };
+        let line_len = from + line_len;
+
+        let mut len = line_len + 2;
+        let mut opened = false;
+        match (kind, declared_len(&rest[1..line_len])) {
+            (b'+' | b'-' | b':' | b'_' | b',' | b'#' | b'(', _) => {}
+            (b'$' | b'=' | b'*' | b'%' | b'~' | b'>', Some(-1)) => {}
+            (b'$' | b'=', Some(n)) if (0..=MAX_BULK_LEN as i64).contains(&n) => {
+                len += n as usize + 2;
+                if rest.len() < len {
+                    return Scan::Incomplete;
+                }
+            }
+            (b'*' | b'%' | b'~' | b'>', Some(n))
+                if (0..=MAX_AGGREGATE_LEN as i64).contains(&n) && progress.pending.len() < MAX_NESTING =>
+            {
+                // A map's elements come in pairs
+                let n = if kind == b'%' { n as u64 * 2 } else { n as u64 };
+                if n > 0 {
+                    progress.pending.push(n);
+                    opened = true;
+                }
+            }
+            _ => return Scan::Invalid,
+        }
+        progress.offset += len;
+        progress.searched = 0;
+        if opened {
+            continue;
+        }
+
+        // The element is done, and so is any aggregate it was the last element of
+        loop {
+            let Some(remaining) = progress.pending.last_mut() else {
+                return Scan::Complete;
+            };
+            *remaining -= 1;
+            if *remaining > 0 {
+                break;
+            }
+            progress.pending.pop();
+        }
+    }
+}
+
+// Reads and writes RESP frames on a byte stream. Input is buffered until it holds a whole frame, and a
+// buffer holding several frames (e.g. from a client pipelining requests) yields them one at a time.
+#[derive(Debug, Clone)]
+pub struct RespCodec {
+    // Input past this without a complete frame is an error, so a client can't use up all our memory
+    pub max_frame_len: usize,
+
+    // Whether to accept inline commands as well as RESP arrays (for the server side of a connection)
+    pub inline: bool,
+
+    // How values are encoded
+    pub protocol: Protocol,
+
+    progress: Progress,
+}
+
+impl Default for RespCodec {
+    fn default() -> Self {
+        Self {
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            inline: false,
+            protocol: Protocol::default(),
+            progress: Progress::default(),
+        }
+    }
+}
+
+impl RespCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Value;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Value>, Error> {
+        // The same choice as `parse_request_streaming` makes. Inline commands are short enough to be
+        // parsed again each time.
+        let inline = self.inline && src.first() != Some(&b'*');
+        if !inline {
+            if self.progress.offset > src.len() {
+                self.progress = Progress::default();
+            }
+            match scan(src, &mut self.progress) {
+                Scan::Incomplete if src.len() > self.max_frame_len => {
+                    return Err(Error::Protocol("frame too large".to_string()));
+                }
+                Scan::Incomplete => return Ok(None),
+                Scan::Complete | Scan::Invalid => {}
+            }
+        }
+        self.progress = Progress::default();
+
+        let parsed = if self.inline {
+            parse_request_streaming(src)
+        } else {
+            parse_value_streaming(src)
+        };
+
+        match parsed {
+            Ok((remaining, value)) => {
+                let len = src.len() - remaining.len();
+                if len > self.max_frame_len {
+                    return Err(Error::Protocol("frame too large".to_string()));
+                }
+                src.advance(len);
+                Ok(Some(value))
+            }
+            Err(nom::Err::Incomplete(_)) if src.len() > self.max_frame_len => {
+                Err(Error::Protocol("frame too large".to_string()))
+            }
+            Err(nom::Err::Incomplete(_)) => Ok(None),
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) if e.code == ErrorKind::TooLarge && inline => {
+                Err(Error::Protocol("too big inline request".to_string()))
+            }
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) if e.code == ErrorKind::TooLarge => {
+                Err(Error::Protocol("length too large".to_string()))
+            }
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) if e.code == ErrorKind::Escaped => {
+                Err(Error::Protocol("unbalanced quotes in request".to_string()))
+            }
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) if e.code == ErrorKind::Count => {
+                Err(Error::Protocol("too deeply nested".to_string()))
+            }
+            Err(_) => Err(Error::Protocol("invalid frame".to_string())),
+        }
+    }
+}
+
+impl Encoder<Value> for RespCodec {
+    type Error = Error;
+
+    fn encode(&mut self, value: Value, dst: &mut BytesMut) -> Result<(), Error> {
+        dst.extend_from_slice(&value.encode(self.protocol));
+        Ok(())
+    }
+}
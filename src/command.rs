@@ -1,81 +1,220 @@
-use crate::resp::RESP;
+use rustdb_derive::RespCommand;
+
+use crate::error::Error;
 use crate::value::Value;
 
-#[derive(Debug, Clone, PartialEq)]
+// Each variant is one command, with its arguments as fields. The RESP encoding and decoding, arity
+// checks, and the table of names and usage strings are all generated from the `#[resp]` attributes
+// (see the rustdb-derive crate), so adding a command only means adding a variant and running it in
+// the server.
+#[derive(Debug, Clone, PartialEq, RespCommand)]
 pub enum Command {
+    #[resp(usage = "GET <key>")]
     Get(String),
-    Set(String, Value),
+    // SET key value [EX seconds | PX milliseconds], with the expiry stored in milliseconds
+    #[resp(
+        usage = "SET <key> <value> [EX <seconds> | PX <milliseconds>]",
+        parse = parse_set_args,
+        encode = encode_set_args
+    )]
+    Set(String, Value, Option<u64>),
+    #[resp(usage = "INC <key>")]
     Inc(String),
+    #[resp(usage = "DEC <key>")]
     Dec(String),
-    Hello,
+    #[resp(usage = "DEL <key>")]
+    Del(String),
+    // SCAN cursor [MATCH pattern] [COUNT n]
+    #[resp(
+        usage = "SCAN <cursor> [MATCH <pattern>] [COUNT <count>]",
+        parse = parse_scan_args,
+        encode = encode_scan_args
+    )]
+    Scan(u64, Option<String>, Option<usize>),
+    #[resp(usage = "EXPIRE <key> <seconds>")]
+    Expire(String, i64),
+    #[resp(usage = "PEXPIRE <key> <milliseconds>")]
+    PExpire(String, i64),
+    // Expires a key at an absolute Unix time in milliseconds
+    #[resp(usage = "PEXPIREAT <key> <unix-time-milliseconds>")]
+    PExpireAt(String, u64),
+    #[resp(usage = "TTL <key>")]
+    Ttl(String),
+    #[resp(usage = "PTTL <key>")]
+    PTtl(String),
+    #[resp(usage = "PERSIST <key>")]
+    Persist(String),
+    // Switches the connection to another protocol version
+    #[resp(usage = "HELLO [protover]")]
+    Hello(Option<u64>),
+    #[resp(usage = "PING [message]")]
+    Ping(Option<String>),
+    #[resp(usage = "SAVE")]
     Save,
+    #[resp(usage = "BGSAVE")]
+    BgSave,
+    #[resp(usage = "LASTSAVE")]
+    LastSave,
+    #[resp(usage = "BGREWRITEAOF")]
+    BgRewriteAof,
+    #[resp(usage = "INFO [section]")]
+    Info(Option<String>),
     // User-side commands (the server should never see these)
-    Help,
+    #[resp(usage = "EXIT", client_only)]
     Exit,
+    #[resp(usage = "HELP", client_only)]
+    Help,
 }
 
-// TODO: Yes, this is super boilerplate-y. There's probably some way to generate all of these functions
-// from the enum definition using a macro, but I haven't written it yet.
-
-impl RESP for Command {
-    fn encode_resp(self) -> String {
-        match self {
-            Self::Get(key) => Value::encode_resp(Value::Array(vec![
-                Value::String("GET".to_string()),
-                Value::String(key),
-            ])),
-            Self::Set(key, value) => Value::encode_resp(Value::Array(vec![
-                Value::String("SET".to_string()),
-                Value::String(key),
-                value,
-            ])),
-            Self::Inc(key) => Value::encode_resp(Value::Array(vec![
-                Value::String("INC".to_string()),
-                Value::String(key),
-            ])),
-            Self::Dec(key) => Value::encode_resp(Value::Array(vec![
-                Value::String("DEC".to_string()),
-                Value::String(key),
-            ])),
-            Self::Hello => {
-                Value::encode_resp(Value::Array(vec![Value::String("HELLO".to_string())]))
-            }
-            Self::Save => Value::encode_resp(Value::Array(vec![Value::String("SAVE".to_string())])),
-            _ => unreachable!(),
+// A type that can be a command argument, for the fields of `Command`
+pub trait Arg: Sized {
+    fn from_arg(value: &Value) -> Result<Self, Error>;
+    fn into_arg(self) -> Value;
+}
+
+// Keys and other names are strings, so an argument that isn't valid UTF-8 is refused rather than
+// silently mangled
+impl Arg for String {
+    fn from_arg(value: &Value) -> Result<Self, Error> {
+        match value {
+            Value::String(s) => Ok(s.clone()),
+            Value::Int(i) => Ok(i.to_string()),
+            _ => Err(Error::InvalidArgument("arguments must be valid UTF-8 strings".to_string())),
         }
     }
 
-    fn decode_resp(s: String) -> Result<Self, String> {
-        let value = Value::decode_resp(s)?;
-        let Value::Array(a) = value else {
-            return Err("expected array of strings".to_string());
-        };
+    fn into_arg(self) -> Value {
+        Value::String(self)
+    }
+}
 
-        let Some(Value::String(cmd)) = a.first() else {
-            return Err("expected array of strings".to_string());
-        };
+impl Arg for i64 {
+    fn from_arg(value: &Value) -> Result<Self, Error> {
+        parse_integer(value)
+    }
 
-        match cmd.as_str() {
-            "GET" => {
-                let key = a.get(1).unwrap();
-                Ok(Self::Get(key.to_string()))
-            }
-            "SET" => {
-                let key = a.get(1).unwrap();
-                let value = a.get(2).unwrap();
-                Ok(Self::Set(key.to_string(), value.clone()))
-            }
-            "INC" => {
-                let key = a.get(1).unwrap();
-                Ok(Self::Inc(key.to_string()))
-            }
-            "DEC" => {
-                let key = a.get(1).unwrap();
-                Ok(Self::Dec(key.to_string()))
+    fn into_arg(self) -> Value {
+        Value::String(self.to_string())
+    }
+}
+
+impl Arg for u64 {
+    fn from_arg(value: &Value) -> Result<Self, Error> {
+        parse_integer(value)
+    }
+
+    fn into_arg(self) -> Value {
+        Value::String(self.to_string())
+    }
+}
+
+impl Arg for Value {
+    fn from_arg(value: &Value) -> Result<Self, Error> {
+        Ok(value.clone())
+    }
+
+    fn into_arg(self) -> Value {
+        self
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &Value) -> Option<T> {
+    match value {
+        Value::Int(i) => i.to_string().parse().ok(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn parse_integer<T: std::str::FromStr>(value: &Value) -> Result<T, Error> {
+    parse_number(value).ok_or(Error::InvalidArgument("value is not an integer or out of range".to_string()))
+}
+
+// Parses the arguments of a SET command (everything after the command name)
+pub fn parse_set_args(args: &[Value]) -> Result<Command, Error> {
+    let usage = || Error::Syntax(Command::usage("SET").unwrap_or_default().to_string());
+    let invalid_time = || Error::InvalidArgument("invalid expire time in 'set' command".to_string());
+
+    match args {
+        [key, value] => Ok(Command::Set(String::from_arg(key)?, value.clone(), None)),
+        [key, value, Value::String(unit), time] => {
+            let time: u64 = match parse_number(time) {
+                Some(time) if time > 0 => time,
+                _ => return Err(invalid_time()),
+            };
+            let ms = match unit.to_uppercase().as_str() {
+                "EX" => time.checked_mul(1000).ok_or_else(invalid_time)?,
+                "PX" => time,
+                _ => return Err(usage()),
+            };
+            Ok(Command::Set(String::from_arg(key)?, value.clone(), Some(ms)))
+        }
+        [] | [_] => Err(Error::WrongArity("SET".to_string())),
+        _ => Err(usage()),
+    }
+}
+
+// Parses the arguments of a SCAN command (everything after the command name)
+pub fn parse_scan_args(args: &[Value]) -> Result<Command, Error> {
+    let usage = || Error::Syntax(Command::usage("SCAN").unwrap_or_default().to_string());
+
+    let Some((cursor, mut options)) = args.split_first() else {
+        return Err(Error::WrongArity("SCAN".to_string()));
+    };
+    let cursor = parse_number(cursor).ok_or(Error::InvalidArgument("invalid cursor".to_string()))?;
+
+    let mut pattern = None;
+    let mut count = None;
+    while let [Value::String(option), argument, rest @ ..] = options {
+        match option.to_uppercase().as_str() {
+            "MATCH" => {
+                let Value::String(p) = argument else {
+                    return Err(usage());
+                };
+                pattern = Some(p.clone());
             }
-            "HELLO" => Ok(Self::Hello),
-            "SAVE" => Ok(Self::Save),
-            _ => Err("unknown command".to_string()),
+            "COUNT" => match parse_number(argument) {
+                Some(n) if n > 0 => count = Some(n),
+                _ => return Err(Error::InvalidArgument("invalid count".to_string())),
+            },
+            _ => return Err(usage()),
+        }
+        options = rest;
+    }
+
+    if !options.is_empty() {
+        return Err(usage());
+    }
+    Ok(Command::Scan(cursor, pattern, count))
+}
+
+// The arguments of a SET command, the other way round. Expiry times are written in seconds when they
+// can be, to match what was most likely sent.
+fn encode_set_args(key: String, value: Value, expiry: Option<u64>) -> Vec<Value> {
+    let mut args = vec![Value::String(key), value];
+    match expiry {
+        Some(ms) if ms % 1000 == 0 => {
+            args.push(Value::String("EX".to_string()));
+            args.push(Value::String((ms / 1000).to_string()));
+        }
+        Some(ms) => {
+            args.push(Value::String("PX".to_string()));
+            args.push(Value::String(ms.to_string()));
         }
+        None => {}
+    }
+    args
+}
+
+fn encode_scan_args(cursor: u64, pattern: Option<String>, count: Option<usize>) -> Vec<Value> {
+    let mut args = vec![Value::String(cursor.to_string())];
+    if let Some(pattern) = pattern {
+        args.push(Value::String("MATCH".to_string()));
+        args.push(Value::String(pattern));
+    }
+    if let Some(count) = count {
+        args.push(Value::String("COUNT".to_string()));
+        args.push(Value::String(count.to_string()));
     }
+    args
 }
@@ -0,0 +1,110 @@
+use std::fmt;
+use std::io;
+
+use crate::snapshot::SnapshotError;
+use crate::value::Value;
+
+// Everything that can go wrong while decoding or running a command. Each kind is sent to clients
+// as a RESP error, starting with the same prefix Redis would use so that client libraries can tell
+// them apart.
+#[derive(Debug)]
+pub enum Error {
+    // Input that isn't valid RESP (or isn't a command at all)
+    Protocol(String),
+    UnknownCommand(String),
+    // The name of a command given the wrong number of arguments
+    WrongArity(String),
+    // Arguments that don't fit the command, holding its usage
+    Syntax(String),
+    // An argument of the wrong type or out of range, e.g. an expire time that isn't a number
+    InvalidArgument(String),
+    // An operation against a key holding the wrong kind of value, e.g. INC on a string
+    WrongType,
+    // A write that would take the server over its memory limit
+    OutOfMemory,
+    // HELLO with a protocol version the server doesn't speak
+    NoProto,
+    // A command that can't run right now or in the server's configuration, e.g. a second BGSAVE
+    Unavailable(String),
+    Io(io::Error),
+}
+
+impl Error {
+    // The first word of the error as sent to clients
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Error::WrongType => "WRONGTYPE",
+            Error::OutOfMemory => "OOM",
+            Error::NoProto => "NOPROTO",
+            _ => "ERR",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Protocol(message) => write!(f, "Protocol error: {}", message),
+            Error::UnknownCommand(name) => write!(f, "unknown command '{}'", name),
+            Error::WrongArity(name) => write!(f, "wrong number of arguments for '{}' command", name.to_lowercase()),
+            Error::Syntax(usage) => write!(f, "Invalid usage: {}", usage),
+            Error::InvalidArgument(message) | Error::Unavailable(message) => write!(f, "{}", message),
+            Error::WrongType => write!(f, "Operation against a key holding the wrong kind of value"),
+            Error::OutOfMemory => write!(f, "command not allowed when used memory > 'maxmemory'"),
+            Error::NoProto => write!(f, "unsupported protocol version"),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+// io::Error can't be compared, so two I/O errors count as equal if they say the same thing
+impl PartialEq for Error {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Error::Protocol(a), Error::Protocol(b))
+            | (Error::UnknownCommand(a), Error::UnknownCommand(b))
+            | (Error::WrongArity(a), Error::WrongArity(b))
+            | (Error::Syntax(a), Error::Syntax(b))
+            | (Error::InvalidArgument(a), Error::InvalidArgument(b))
+            | (Error::Unavailable(a), Error::Unavailable(b)) => a == b,
+            (Error::WrongType, Error::WrongType)
+            | (Error::OutOfMemory, Error::OutOfMemory)
+            | (Error::NoProto, Error::NoProto) => true,
+            (Error::Io(a), Error::Io(b)) => a.kind() == b.kind() && a.to_string() == b.to_string(),
+            _ => false,
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<SnapshotError> for Error {
+    fn from(e: SnapshotError) -> Self {
+        match e {
+            SnapshotError::Io(e) => Error::Io(e),
+            e => Error::Io(io::Error::other(e)),
+        }
+    }
+}
+
+// The error as a client sees it, e.g. "WRONGTYPE Operation against a key holding the wrong kind of value".
+// Errors can quote what the client sent, so line breaks are replaced with spaces like Redis does.
+// Otherwise they would end the reply early and the rest would be read as another one.
+impl From<Error> for Value {
+    fn from(e: Error) -> Self {
+        Value::SimpleError(format!("{} {}", e.prefix(), e).replace(['\r', '\n'], " "))
+    }
+}
@@ -0,0 +1,204 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::hash::BuildHasher;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::str::FromStr;
+
+use crate::table::{unix_time_ms, Table};
+use crate::value::{format_double, is_big_number, verbatim_string, Value};
+
+// Converting the keyspace to and from text formats, for seeding and inspecting databases with other tools.
+//
+// Values map onto JSON as naturally as possible: strings, integers, booleans, null and arrays are the
+// JSON equivalents. Simple strings and errors would be indistinguishable from strings that way, so they
+// are wrapped in an object instead: {"simple_string": "OK"} and {"simple_error": "ERR"}. Binary values
+// that aren't valid UTF-8 can't be JSON strings either, so they become {"base64": "..."}. The other
+// RESP3 types are wrapped the same way, with maps as lists of pairs since their keys needn't be strings,
+// and doubles as strings since JSON has no infinity or NaN.
+
+pub fn value_to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::String(s) => json!(s),
+        Value::Int(i) => json!(i),
+        Value::Array(a) => serde_json::Value::Array(a.iter().map(value_to_json).collect()),
+        Value::Null => serde_json::Value::Null,
+        Value::SimpleString(s) => json!({ "simple_string": s }),
+        Value::SimpleError(s) => json!({ "simple_error": s }),
+        Value::Bytes(b) => json!({ "base64": BASE64.encode(b) }),
+        Value::Boolean(b) => json!(b),
+        Value::Map(m) => json!({
+            "map": m.iter().map(|(k, v)| json!([value_to_json(k), value_to_json(v)])).collect::<Vec<_>>()
+        }),
+        Value::Set(s) => json!({ "set": s.iter().map(value_to_json).collect::<Vec<_>>() }),
+        Value::Push(p) => json!({ "push": p.iter().map(value_to_json).collect::<Vec<_>>() }),
+        Value::Double(d) => json!({ "double": format_double(*d) }),
+        Value::BigNumber(n) => json!({ "big_number": n }),
+        Value::VerbatimString(format, text) => json!({ "verbatim_string": format!("{}:{}", format, text) }),
+    }
+}
+
+pub fn value_from_json(json: &serde_json::Value) -> Result<Value, String> {
+    match json {
+        serde_json::Value::String(s) => Ok(Value::String(s.clone())),
+        serde_json::Value::Number(n) => n
+            .as_i64()
+            .map(Value::Int)
+            .ok_or(format!("not a 64-bit integer: {}", n)),
+        serde_json::Value::Array(a) => a.iter().map(value_from_json).collect::<Result<_, _>>().map(Value::Array),
+        serde_json::Value::Null => Ok(Value::Null),
+        serde_json::Value::Bool(b) => Ok(Value::Boolean(*b)),
+        serde_json::Value::Object(o) if o.len() == 1 => match o.iter().next().unwrap() {
+            (tag, serde_json::Value::String(s)) if tag == "simple_string" => Ok(Value::SimpleString(s.clone())),
+            (tag, serde_json::Value::String(s)) if tag == "simple_error" => Ok(Value::SimpleError(s.clone())),
+            (tag, serde_json::Value::String(s)) if tag == "base64" => BASE64
+                .decode(s)
+                .map(Value::Bytes)
+                .map_err(|e| format!("invalid base64 value: {}", e)),
+            (tag, serde_json::Value::String(s)) if tag == "double" => {
+                s.parse().map(Value::Double).map_err(|_| format!("invalid double: {}", s))
+            }
+            (tag, serde_json::Value::String(s)) if tag == "big_number" && is_big_number(s) => {
+                Ok(Value::BigNumber(s.clone()))
+            }
+            (tag, serde_json::Value::String(s)) if tag == "verbatim_string" => {
+                verbatim_string(s).ok_or(format!("invalid verbatim string: {}", s))
+            }
+            (tag, serde_json::Value::Array(a)) if tag == "map" => a
+                .iter()
+                .map(|pair| match pair.as_array().map(Vec::as_slice) {
+                    Some([k, v]) => Ok((value_from_json(k)?, value_from_json(v)?)),
+                    _ => Err(format!("not a key-value pair: {}", pair)),
+                })
+                .collect::<Result<_, _>>()
+                .map(Value::Map),
+            (tag, serde_json::Value::Array(a)) if tag == "set" => {
+                a.iter().map(value_from_json).collect::<Result<_, _>>().map(Value::Set)
+            }
+            (tag, serde_json::Value::Array(a)) if tag == "push" => {
+                a.iter().map(value_from_json).collect::<Result<_, _>>().map(Value::Push)
+            }
+            _ => Err(format!("unknown value: {}", json)),
+        },
+        _ => Err(format!("unknown value: {}", json)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // One JSON object per line: {"key": ..., "value": ..., "expires_at": ...}
+    JsonLines,
+    // A header row, then key,value,expires_at with the value encoded as JSON
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "jsonl" | "json" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format: {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonRecord {
+    key: String,
+    value: serde_json::Value,
+    // Unix time in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CsvRecord {
+    key: String,
+    value: String,
+    expires_at: Option<u64>,
+}
+
+// Writes every entry in the table, returning how many were written
+pub fn export<S>(table: &Table<String, Value, S>, format: Format, writer: impl Write) -> Result<usize, String> {
+    let mut count = 0;
+    match format {
+        Format::JsonLines => {
+            let mut writer = writer;
+            for entry in table.entries() {
+                let record = JsonRecord {
+                    key: entry.key().clone(),
+                    value: value_to_json(entry.value()),
+                    expires_at: entry.expires_at(),
+                };
+                serde_json::to_writer(&mut writer, &record).map_err(|e| e.to_string())?;
+                writer.write_all(b"\n").map_err(|e| e.to_string())?;
+                count += 1;
+            }
+            writer.flush().map_err(|e| e.to_string())?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for entry in table.entries() {
+                let record = CsvRecord {
+                    key: entry.key().clone(),
+                    value: value_to_json(entry.value()).to_string(),
+                    expires_at: entry.expires_at(),
+                };
+                writer.serialize(record).map_err(|e| e.to_string())?;
+                count += 1;
+            }
+            writer.flush().map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(count)
+}
+
+// Reads entries into the table, replacing any existing keys with the same name. Entries that have
+// already expired are skipped. Returns how many entries were imported.
+pub fn import<S: BuildHasher>(
+    table: &mut Table<String, Value, S>,
+    format: Format,
+    reader: impl Read,
+) -> Result<usize, String> {
+    let mut records = Vec::new();
+    match format {
+        Format::JsonLines => {
+            for (i, line) in BufReader::new(reader).lines().enumerate() {
+                let line = line.map_err(|e| e.to_string())?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record: JsonRecord =
+                    serde_json::from_str(&line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+                let value = value_from_json(&record.value).map_err(|e| format!("line {}: {}", i + 1, e))?;
+                records.push((record.key, value, record.expires_at));
+            }
+        }
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(reader);
+            for (i, record) in reader.deserialize::<CsvRecord>().enumerate() {
+                // The header is line 1
+                let line = i + 2;
+                let record = record.map_err(|e| format!("line {}: {}", line, e))?;
+                let json = serde_json::from_str(&record.value).map_err(|e| format!("line {}: {}", line, e))?;
+                let value = value_from_json(&json).map_err(|e| format!("line {}: {}", line, e))?;
+                records.push((record.key, value, record.expires_at));
+            }
+        }
+    }
+
+    // Only touch the table once everything has been read successfully
+    let now = unix_time_ms();
+    let mut count = 0;
+    for (key, value, expires_at) in records {
+        if expires_at.is_some_and(|t| t <= now) {
+            continue;
+        }
+        table.set_with_expiry(key, value, expires_at);
+        count += 1;
+    }
+    Ok(count)
+}
@@ -0,0 +1,124 @@
+// Glob-style pattern matching, as used by Redis for SCAN's MATCH option.
+// Supports `*` (any run of characters), `?` (any single character), `[abc]`, `[a-z]` and `[^abc]`
+// character classes, and `\` to match the next character literally.
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    AnyRun,
+    AnyChar,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+    Literal(char),
+}
+
+impl Token {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Token::AnyRun | Token::AnyChar => true,
+            Token::Class { negated, ranges } => {
+                ranges.iter().any(|(low, high)| *low <= c && c <= *high) != *negated
+            }
+            Token::Literal(l) => *l == c,
+        }
+    }
+}
+
+fn tokenize(pattern: &str) -> Vec<Token> {
+    let chars = pattern.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' => {
+                // Consecutive stars are equivalent to a single one
+                if tokens.last() != Some(&Token::AnyRun) {
+                    tokens.push(Token::AnyRun);
+                }
+                i += 1;
+            }
+            '?' => {
+                tokens.push(Token::AnyChar);
+                i += 1;
+            }
+            '\\' if i + 1 < chars.len() => {
+                tokens.push(Token::Literal(chars[i + 1]));
+                i += 2;
+            }
+            '[' => match parse_class(&chars[i + 1..]) {
+                Some((token, len)) => {
+                    tokens.push(token);
+                    i += 1 + len;
+                }
+                // An unterminated class is matched literally
+                None => {
+                    tokens.push(Token::Literal('['));
+                    i += 1;
+                }
+            },
+            c => {
+                tokens.push(Token::Literal(c));
+                i += 1;
+            }
+        }
+    }
+
+    tokens
+}
+
+// Parses the character class just after a `[`. Returns the class along with the number of
+// characters it took up, including the closing `]`.
+fn parse_class(chars: &[char]) -> Option<(Token, usize)> {
+    let negated = chars.first() == Some(&'^');
+    let mut i = if negated { 1 } else { 0 };
+    let mut ranges = Vec::new();
+
+    loop {
+        let c = match *chars.get(i)? {
+            ']' => break,
+            '\\' => {
+                i += 1;
+                *chars.get(i)?
+            }
+            c => c,
+        };
+
+        if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|end| *end != ']') {
+            let end = chars[i + 2];
+            ranges.push(if c <= end { (c, end) } else { (end, c) });
+            i += 3;
+        } else {
+            ranges.push((c, c));
+            i += 1;
+        }
+    }
+
+    Some((Token::Class { negated, ranges }, i + 1))
+}
+
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let tokens = tokenize(pattern);
+
+    // matched[j] is whether the tokens seen so far match the first j characters of the string.
+    // Runs in O(tokens * chars), so a pattern like "*a*a*a*b" can't blow up.
+    let mut matched = vec![false; s.chars().count() + 1];
+    matched[0] = true;
+
+    for token in tokens.iter() {
+        let mut next = vec![false; matched.len()];
+        if *token == Token::AnyRun {
+            next[0] = matched[0];
+        }
+        for (j, c) in s.chars().enumerate() {
+            next[j + 1] = match token {
+                Token::AnyRun => matched[j + 1] || next[j],
+                _ => matched[j] && token.matches(c),
+            };
+        }
+        matched = next;
+    }
+
+    matched[matched.len() - 1]
+}
@@ -1,4 +1,17 @@
+// So that code generated by rustdb-derive can name this crate the same way from inside and out
+extern crate self as rustdb;
+
 pub mod resp;
 pub mod table;
+pub mod hash;
 pub mod command;
-pub mod value;
\ No newline at end of file
+pub mod value;
+pub mod glob;
+pub mod memory;
+pub mod aof;
+pub mod snapshot;
+pub mod export;
+pub mod codec;
+pub mod error;
+
+pub use error::Error;
@@ -13,10 +13,10 @@ fn main() {
     //         Value::String("world".to_string()),
     //     ]),
     // ]);
-    let mut _table = Table::new();
-    let v = Command::Set("Hello".to_string(), Value::String("world".to_string()));
+    let mut _table: Table<String, Value> = Table::new();
+    let v = Command::Set("Hello".to_string(), Value::String("world".to_string()), None);
     let encoded = v.clone().encode_resp();
-    let decoded = Command::decode_resp(encoded).unwrap();
+    let decoded = Command::decode_resp(&encoded).unwrap();
     println!("{:?}", decoded);
     assert!(decoded == v);
 }
@@ -35,13 +35,1323 @@ fn test_table() {
 
     for i in 0..1000 {
         assert!(
-            table.get(&format!("string/{}", i)) == Some(Value::String(format!("{}", i)))
+            table.get(&format!("string/{}", i)) == Some(&Value::String(format!("{}", i)))
         );
-        assert!(table.get(&format!("int/{}", i)) == Some(Value::Int(i)));
+        assert!(table.get(&format!("int/{}", i)) == Some(&Value::Int(i)));
     }
 
     let serialized = bincode::serialize(&table).unwrap();
-    let deserialized = bincode::deserialize::<Table>(&serialized).unwrap();
+    let deserialized = bincode::deserialize::<Table<String, Value>>(&serialized).unwrap();
 
     assert!(deserialized == table);
 }
+
+#[test]
+fn test_remove() {
+    let mut table = Table::new();
+
+    for i in 0..1000 {
+        table.set(format!("key/{}", i), Value::Int(i));
+    }
+    assert!(table.len() == 1000);
+
+    for i in 0..1000 {
+        if i % 3 != 0 {
+            assert!(table.remove(&format!("key/{}", i)) == Some(Value::Int(i)));
+        }
+    }
+    assert!(table.remove("key/1").is_none());
+
+    for i in 0..1000 {
+        let expected = if i % 3 == 0 { Some(&Value::Int(i)) } else { None };
+        assert!(table.get(&format!("key/{}", i)) == expected);
+    }
+
+    // Removing every key should merge the table all the way back down
+    for i in (0..1000).step_by(3) {
+        assert!(table.remove(&format!("key/{}", i)) == Some(Value::Int(i)));
+    }
+    assert!(table.is_empty());
+    assert!(table == Table::new());
+}
+
+#[test]
+fn test_controlled_splitting() {
+    use rustdb::table::TableConfig;
+
+    let config = TableConfig {
+        load_factor: 4.0,
+        bucket_capacity: 4,
+    };
+    let mut table = Table::with_config(config);
+
+    for i in 0..1000 {
+        table.set(format!("key/{}", i), Value::Int(i));
+        assert!(table.load_factor() <= config.load_factor);
+    }
+    assert!(table.bucket_count() == 250);
+
+    for i in 0..1000 {
+        assert!(table.get(&format!("key/{}", i)) == Some(&Value::Int(i)));
+    }
+
+    // A snapshot with a load factor that isn't positive is refused rather than loaded
+    let serialized = bincode::serialize(&table).unwrap();
+    let at = serialized.windows(8).position(|bytes| bytes == 4.0f64.to_le_bytes()).unwrap();
+    for load_factor in [0.0, -1.0, f64::NAN] {
+        let mut damaged = serialized.clone();
+        damaged[at..at + 8].copy_from_slice(&f64::to_le_bytes(load_factor));
+        assert!(bincode::deserialize::<Table<String, Value>>(&damaged).is_err());
+    }
+}
+
+#[test]
+fn test_generic_table() {
+    use std::collections::hash_map::RandomState;
+
+    let mut table: Table<u64, Vec<u8>, RandomState> = Table::with_hasher(RandomState::new());
+    for i in 0..1000u64 {
+        table.set(i, i.to_le_bytes().to_vec());
+    }
+    for i in 0..1000u64 {
+        assert!(table.get(&i) == Some(&i.to_le_bytes().to_vec()));
+    }
+    assert!(table.remove(&0) == Some(vec![0; 8]));
+    assert!(table.get(&0).is_none());
+
+    let mut table: Table<(u32, bool), i32> = Table::new();
+    table.set((7, true), -7);
+    let serialized = bincode::serialize(&table).unwrap();
+    let mut deserialized = bincode::deserialize::<Table<(u32, bool), i32>>(&serialized).unwrap();
+    assert!(deserialized.get(&(7, true)) == Some(&-7));
+
+    // Keys and values don't need to implement MemoryUsage unless the table tracks its memory
+    let mut table: Table<std::net::Ipv4Addr, std::time::Duration> = Table::new();
+    table.set(std::net::Ipv4Addr::LOCALHOST, std::time::Duration::from_secs(1));
+    assert!(table.get(&std::net::Ipv4Addr::LOCALHOST) == Some(&std::time::Duration::from_secs(1)));
+}
+
+#[test]
+fn test_keyed_hashing_resists_collisions() {
+    use rustdb::hash::{FnvBuildHasher, SipBuildHasher};
+    use std::hash::BuildHasher;
+
+    // Find keys whose FNV hashes agree on the low 12 bits, so that they all map to the same
+    // bucket in any FNV table with fewer than 4096 buckets.
+    let target = FnvBuildHasher.hash_one("key/0") & 0xfff;
+    let keys = (0..)
+        .map(|i| format!("key/{}", i))
+        .filter(|key| FnvBuildHasher.hash_one(key.as_str()) & 0xfff == target)
+        .take(32)
+        .collect::<Vec<_>>();
+
+    let mut fnv_table = Table::new();
+    let mut sip_table = Table::with_hasher(SipBuildHasher::random());
+    for key in keys.iter() {
+        fnv_table.set(key.clone(), Value::Null);
+        sip_table.set(key.clone(), Value::Null);
+    }
+
+    assert!(fnv_table.bucket_lens().max() == Some(keys.len()));
+    assert!(sip_table.bucket_lens().max().unwrap() < keys.len() / 2);
+
+    // The seed is part of the snapshot, so lookups still work after a round trip
+    let serialized = bincode::serialize(&sip_table).unwrap();
+    let mut deserialized =
+        bincode::deserialize::<Table<String, Value, SipBuildHasher>>(&serialized).unwrap();
+    assert!(deserialized == sip_table);
+    for key in keys.iter() {
+        assert!(deserialized.get(key) == Some(&Value::Null));
+    }
+}
+
+#[test]
+fn test_iter() {
+    let mut table = Table::new();
+    for i in 0..1000 {
+        table.set(format!("key/{}", i), Value::Int(i));
+    }
+
+    let mut keys = table.keys().cloned().collect::<Vec<_>>();
+    keys.sort();
+    let mut expected = (0..1000).map(|i| format!("key/{}", i)).collect::<Vec<_>>();
+    expected.sort();
+    assert!(keys == expected);
+
+    assert!(table.values().count() == 1000);
+    let entries = table
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect::<Vec<_>>();
+    assert!(entries.iter().all(|(key, value)| table.get(key) == Some(value)));
+}
+
+#[test]
+fn test_scan_survives_splits() {
+    use std::collections::HashSet;
+
+    let mut table = Table::new();
+    for i in 0..1000 {
+        table.set(format!("key/{}", i), Value::Int(i));
+    }
+
+    // Grow the table between every call, and check that none of the original keys are missed
+    let mut seen = HashSet::new();
+    let mut cursor = 0;
+    let mut i = 1000;
+    loop {
+        let (next, entries) = table.scan(cursor, 10);
+        seen.extend(entries.iter().map(|entry| entry.key().clone()));
+        cursor = next;
+        if cursor == 0 {
+            break;
+        }
+
+        for _ in 0..20 {
+            table.set(format!("key/{}", i), Value::Int(i));
+            i += 1;
+        }
+    }
+
+    for i in 0..1000 {
+        assert!(seen.contains(&format!("key/{}", i)));
+    }
+
+    // Likewise when the table shrinks between calls
+    let mut seen = HashSet::new();
+    let mut cursor = 0;
+    loop {
+        let (next, entries) = table.scan(cursor, 10);
+        seen.extend(entries.iter().map(|entry| entry.key().clone()));
+        cursor = next;
+        if cursor == 0 {
+            break;
+        }
+
+        for _ in 0..20 {
+            i -= 1;
+            if i >= 1000 {
+                table.remove(&format!("key/{}", i));
+            }
+        }
+    }
+
+    for i in 0..1000 {
+        assert!(seen.contains(&format!("key/{}", i)));
+    }
+}
+
+#[test]
+fn test_glob_match() {
+    use rustdb::glob::glob_match;
+
+    assert!(glob_match("*", ""));
+    assert!(glob_match("user:*", "user:42"));
+    assert!(!glob_match("user:*", "session:42"));
+    assert!(glob_match("h?llo", "hello"));
+    assert!(!glob_match("h?llo", "hllo"));
+    assert!(glob_match("h[ae]llo", "hallo"));
+    assert!(!glob_match("h[^e]llo", "hello"));
+    assert!(glob_match("key/[0-9]*", "key/7abc"));
+    assert!(glob_match("\\*", "*"));
+    assert!(!glob_match("\\*", "a"));
+    assert!(glob_match("[", "["));
+    assert!(!glob_match("*a*a*a*a*a*a*a*a*b", &"a".repeat(100)));
+}
+
+#[test]
+fn test_scan_command() {
+    use rustdb::command::parse_scan_args;
+
+    let command = Command::Scan(17, Some("user:*".to_string()), Some(100));
+    assert!(Command::decode_resp(&command.clone().encode_resp()) == Ok(command));
+
+    let args = [
+        Value::Int(0),
+        Value::String("count".to_string()),
+        Value::Int(5),
+    ];
+    assert!(parse_scan_args(&args) == Ok(Command::Scan(0, None, Some(5))));
+    assert!(parse_scan_args(&[]).is_err());
+    assert!(parse_scan_args(&[Value::Int(0), Value::String("MATCH".to_string())]).is_err());
+}
+
+#[test]
+fn test_expiry() {
+    use rustdb::table::unix_time_ms;
+
+    let mut table = Table::new();
+    let now = unix_time_ms();
+    for i in 0..100 {
+        table.set_with_expiry(format!("expired/{}", i), Value::Int(i), Some(now - 1));
+        table.set_with_expiry(format!("later/{}", i), Value::Int(i), Some(now + 3_600_000));
+        table.set(format!("forever/{}", i), Value::Int(i));
+    }
+    assert!(table.len() == 300);
+
+    // Expired keys are invisible to lookups and removed as they are seen
+    assert!(table.get("expired/0").is_none());
+    assert!(table.len() == 299);
+    assert!(table.remove("expired/1").is_none());
+    assert!(table.len() == 298);
+    assert!(table.iter().count() == 200);
+
+    // Setting a key again clears its expiry
+    table.set("later/0".to_string(), Value::Int(0));
+    assert!(table.get_entry("later/0").unwrap().expires_at().is_none());
+    assert!(table.set_expiry("later/0", Some(now + 1000)));
+    assert!(table.get_entry("later/0").unwrap().expires_at() == Some(now + 1000));
+    assert!(!table.set_expiry("expired/2", None));
+
+    // Sampling every bucket gets rid of the rest
+    let buckets = table.bucket_count();
+    let (checked, removed) = table.expire_sample(buckets * 30);
+    assert!(removed == 97 && checked >= 197);
+    assert!(table.len() == 200);
+
+    // Expiry times survive a round trip through disk
+    let path = std::env::temp_dir().join(format!("rustdb-test-expiry-{}", std::process::id()));
+    table.to_disk(path.to_str().unwrap()).unwrap();
+    let mut loaded = Table::<String, Value>::from_disk(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(loaded.get_entry("later/1").unwrap().expires_at() == Some(now + 3_600_000));
+    assert!(loaded.get_entry("forever/1").unwrap().expires_at().is_none());
+}
+
+#[test]
+fn test_set_command() {
+    use rustdb::command::parse_set_args;
+
+    for expiry in [None, Some(5000), Some(1500)] {
+        let command = Command::Set("k".to_string(), Value::Int(1), expiry);
+        assert!(Command::decode_resp(&command.clone().encode_resp()) == Ok(command));
+    }
+
+    let args = [
+        Value::String("k".to_string()),
+        Value::String("v".to_string()),
+        Value::String("ex".to_string()),
+        Value::Int(10),
+    ];
+    assert!(
+        parse_set_args(&args)
+            == Ok(Command::Set("k".to_string(), Value::String("v".to_string()), Some(10_000)))
+    );
+    assert!(parse_set_args(&args[..3]).is_err());
+}
+
+#[test]
+fn test_memory_usage() {
+    let mut table = Table::new();
+    table.track_memory();
+    let empty = table.memory_usage();
+
+    for i in 0..1000 {
+        table.set(format!("key/{}", i), Value::String("x".repeat(100)));
+    }
+    assert!(table.memory_usage() > empty + 1000 * 100);
+
+    // Growing a value in place is accounted for
+    let before = table.memory_usage();
+    table.update("key/0", |value| *value = Value::String("x".repeat(10_000)));
+    assert!(table.memory_usage() >= before + 9_900);
+
+    for i in 0..1000 {
+        table.remove(&format!("key/{}", i));
+    }
+    assert!(table.memory_usage() == empty);
+
+    // Entries that were there before tracking started are counted too
+    let mut untracked = Table::new();
+    for i in 0..1000 {
+        untracked.set(format!("key/{}", i), Value::String("x".repeat(100)));
+    }
+    let buckets_only = untracked.memory_usage();
+    untracked.track_memory();
+    assert!(untracked.memory_usage() > buckets_only + 1000 * 100);
+
+    // The count isn't saved, so a loaded table starts at zero until it tracks its memory again,
+    // and it doesn't make otherwise identical tables unequal
+    let serialized = bincode::serialize(&untracked).unwrap();
+    let mut deserialized = bincode::deserialize::<Table<String, Value>>(&serialized).unwrap();
+    assert!(deserialized.memory_usage() == buckets_only);
+    assert!(deserialized == untracked);
+    deserialized.track_memory();
+    assert!(deserialized.memory_usage() > buckets_only + 1000 * 100);
+}
+
+#[test]
+fn test_eviction() {
+    use rustdb::table::{unix_time_ms, EvictionPolicy};
+
+    let mut table = Table::new();
+    assert!(table.evict(EvictionPolicy::AllKeysLru).is_none());
+
+    // With few enough keys that every one of them is sampled, the choice is deterministic
+    table.set("old".to_string(), Value::Int(0));
+    table.set("new".to_string(), Value::Int(1));
+    table.get("old");
+    assert!(table.evict(EvictionPolicy::NoEviction).is_none());
+    assert!(table.evict(EvictionPolicy::AllKeysLru).is_some());
+    assert!(table.get("new").is_none() && table.get("old").is_some());
+
+    table.set("soon".to_string(), Value::Int(0));
+    table.set_with_expiry("later".to_string(), Value::Int(1), Some(unix_time_ms() + 60_000));
+    table.set_with_expiry("sooner".to_string(), Value::Int(2), Some(unix_time_ms() + 1_000));
+    assert!(table.evict(EvictionPolicy::VolatileTtl).is_some());
+    assert!(table.get("sooner").is_none());
+    assert!(table.evict(EvictionPolicy::VolatileTtl).is_some());
+    assert!(table.get("later").is_none());
+    // Only keys with an expiry time can be evicted under volatile-ttl
+    assert!(table.evict(EvictionPolicy::VolatileTtl).is_none());
+
+    let mut table = Table::new();
+    table.set("rare".to_string(), Value::Int(0));
+    table.set("popular".to_string(), Value::Int(1));
+    for _ in 0..1000 {
+        table.get("popular");
+    }
+    table.get("rare");
+    assert!(table.evict(EvictionPolicy::AllKeysLfu).is_some());
+    assert!(table.get("rare").is_none() && table.get("popular").is_some());
+
+    assert!(table.evict(EvictionPolicy::AllKeysRandom).is_some());
+    assert!(table.is_empty());
+    // A few volatile keys among many persistent ones are still found
+    let mut table = Table::new();
+    for i in 0..10_000 {
+        table.set(format!("key/{}", i), Value::Int(i));
+    }
+    for i in 0..3 {
+        table.set_with_expiry(format!("volatile/{}", i), Value::Int(i), Some(unix_time_ms() + 60_000));
+    }
+    for _ in 0..3 {
+        let entry = table.evict(EvictionPolicy::VolatileTtl).unwrap();
+        assert!(entry.key().starts_with("volatile/"));
+    }
+    assert!(table.evict(EvictionPolicy::VolatileTtl).is_none());
+    assert!(table.len() == 10_000);
+}
+
+#[test]
+fn test_append_only_file() {
+    use rustdb::aof::{AppendOnlyFile, FsyncPolicy};
+    use std::io::Write;
+
+    let path = std::env::temp_dir().join(format!("rustdb-test-aof-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let commands = vec![
+        Command::Set("a".to_string(), Value::String("héllo".to_string()), None),
+        Command::Set("b".to_string(), Value::Bytes(vec![0, 0xff, b'\r', b'\n']), None),
+        Command::Inc("n".to_string()),
+        Command::PExpireAt("a".to_string(), 1_700_000_000_000),
+        Command::Del("b".to_string()),
+    ];
+    let mut aof = AppendOnlyFile::open(&path, FsyncPolicy::Always).unwrap();
+    aof.append(&commands[..3]).unwrap();
+    aof.append(&commands[3..]).unwrap();
+    assert!(AppendOnlyFile::load(&path).unwrap().0 == commands);
+
+    // Writes made during a rewrite end up after the rewritten commands
+    assert!(aof.start_rewrite());
+    assert!(!aof.start_rewrite());
+    aof.append(&commands[2..3]).unwrap();
+    AppendOnlyFile::write_rewrite(&path, &commands[..2]).unwrap();
+    aof.finish_rewrite().unwrap();
+    assert!(AppendOnlyFile::load(&path).unwrap().0 == commands[..3]);
+
+    // A command cut off part of the way through is ignored
+    let full = std::fs::read(&path).unwrap();
+    let partial = Command::Set("c".to_string(), Value::String("x".to_string()), None).encode_resp();
+    for len in 1..partial.len() {
+        std::fs::write(&path, &full).unwrap();
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&partial[..len]).unwrap();
+        assert!(AppendOnlyFile::load(&path).unwrap() == (commands[..3].to_vec(), full.len() as u64));
+    }
+
+    // It is cut off before anything else is appended, so that the log can be loaded again
+    let (_, loaded_len) = AppendOnlyFile::load(&path).unwrap();
+    let mut aof = AppendOnlyFile::open(&path, FsyncPolicy::Always).unwrap();
+    aof.truncate(loaded_len).unwrap();
+    aof.append(&commands[3..4]).unwrap();
+    assert!(AppendOnlyFile::load(&path).unwrap().0 == commands[..4]);
+
+    // Logs written with the old array encoding still load
+    let legacy = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\nx\r\n\r\n*2\r\n$3\r\nDEL\r\n$1\r\na\r\n\r\n";
+    std::fs::write(&path, legacy).unwrap();
+    assert!(
+        AppendOnlyFile::load(&path).unwrap()
+            == (
+                vec![
+                    Command::Set("a".to_string(), Value::String("x".to_string()), None),
+                    Command::Del("a".to_string()),
+                ],
+                legacy.len() as u64
+            )
+    );
+
+    // But garbage isn't
+    std::fs::write(&path, [full.as_slice(), b"garbage"].concat()).unwrap();
+    assert!(AppendOnlyFile::load(&path).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_save_schedule() {
+    use rustdb::snapshot::{SavePoint, SaveSchedule};
+
+    let schedule: SaveSchedule = "3600 1  300 100".parse().unwrap();
+    assert!(
+        schedule.0
+            == vec![
+                SavePoint { seconds: 3600, changes: 1 },
+                SavePoint { seconds: 300, changes: 100 },
+            ]
+    );
+    assert!(schedule.to_string() == "3600 1 300 100");
+
+    assert!(!schedule.is_due(10_000, 0));
+    assert!(!schedule.is_due(3599, 99));
+    assert!(schedule.is_due(3600, 1));
+    assert!(schedule.is_due(300, 100));
+
+    assert!("".parse::<SaveSchedule>().unwrap().is_empty());
+    assert!("3600".parse::<SaveSchedule>().is_err());
+    assert!("3600 often".parse::<SaveSchedule>().is_err());
+}
+
+#[test]
+fn test_interrupted_snapshot() {
+    use rustdb::snapshot::{temp_path, write_atomically};
+    use std::io::Write;
+
+    let path = std::env::temp_dir().join(format!("rustdb-test-snapshot-{}", std::process::id()));
+    let path_str = path.to_str().unwrap();
+    // The temporary files that writes to `path` have left behind
+    let temp_files = || {
+        let prefix = format!("{}.tmp", path.file_name().unwrap().to_str().unwrap());
+        std::fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_str().unwrap().starts_with(&prefix))
+            .count()
+    };
+
+    let mut table = Table::new();
+    for i in 0..100 {
+        table.set(format!("key/{}", i), Value::Int(i));
+    }
+    table.to_disk(path_str).unwrap();
+
+    // A write that fails part of the way through leaves the previous snapshot alone
+    let bytes = bincode::serialize(&table).unwrap();
+    let result = write_atomically(&path, |writer| {
+        writer.write_all(&bytes[..bytes.len() / 2])?;
+        Err(std::io::Error::other("disk full"))
+    });
+    assert!(result.is_err());
+    assert!(temp_files() == 0);
+    assert!(Table::<String, Value>::from_disk(path_str).unwrap() == table);
+
+    // So does a crash, which leaves a half-written temporary file behind
+    let crashed = temp_path(&path);
+    std::fs::write(&crashed, &bytes[..bytes.len() / 2]).unwrap();
+    assert!(Table::<String, Value>::from_disk(path_str).unwrap() == table);
+
+    // Which the next save doesn't touch
+    table.set("new".to_string(), Value::Int(-1));
+    table.to_disk(path_str).unwrap();
+    assert!(temp_files() == 1 && std::fs::read(&crashed).unwrap() == bytes[..bytes.len() / 2]);
+    assert!(Table::<String, Value>::from_disk(path_str).unwrap() == table);
+    std::fs::remove_file(&crashed).unwrap();
+
+    // Concurrent saves each write their own temporary file, so whichever finishes last wins intact
+    let tables = (0..4)
+        .map(|n| {
+            let mut table = Table::new();
+            for i in 0..10_000 {
+                table.set(format!("key/{}", i), Value::Int(n));
+            }
+            table
+        })
+        .collect::<Vec<_>>();
+    std::thread::scope(|scope| {
+        for table in tables.iter() {
+            scope.spawn(|| table.to_disk(path_str).unwrap());
+        }
+    });
+    let loaded = Table::<String, Value>::from_disk(path_str).unwrap();
+    assert!(tables.contains(&loaded));
+    assert!(temp_files() == 0);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_snapshot_format() {
+    use rustdb::snapshot::{read_snapshot, Compression, Header, SnapshotError, FORMAT_VERSION, HEADER_LEN};
+
+    let path = std::env::temp_dir().join(format!("rustdb-test-format-{}", std::process::id()));
+    let path_str = path.to_str().unwrap();
+
+    let mut table = Table::new();
+    for i in 0..100 {
+        table.set(format!("key/{}", i), Value::Int(i));
+    }
+    table.to_disk(path_str).unwrap();
+
+    let bytes = std::fs::read(&path).unwrap();
+    let (header, loaded) = read_snapshot::<Table<String, Value>>(&path).unwrap();
+    let header = header.unwrap();
+    assert!(loaded == table);
+    assert!(header.version == FORMAT_VERSION);
+    assert!(header.payload_len as usize == bytes.len() - HEADER_LEN);
+    assert!(Header::decode(&header.encode()).unwrap() == Some(header));
+
+    // A flipped bit in the payload is caught by the checksum
+    let mut corrupt = bytes.clone();
+    corrupt[HEADER_LEN + 10] ^= 1;
+    std::fs::write(&path, &corrupt).unwrap();
+    assert!(matches!(
+        Table::<String, Value>::from_disk(path_str),
+        Err(SnapshotError::ChecksumMismatch { .. })
+    ));
+
+    std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+    assert!(matches!(
+        Table::<String, Value>::from_disk(path_str),
+        Err(SnapshotError::Truncated { .. })
+    ));
+
+    let mut future = bytes.clone();
+    future[6..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+    std::fs::write(&path, &future).unwrap();
+    assert!(matches!(
+        Table::<String, Value>::from_disk(path_str),
+        Err(SnapshotError::UnsupportedVersion(_))
+    ));
+
+    std::fs::write(&path, b"definitely not a snapshot").unwrap();
+    assert!(matches!(
+        Table::<String, Value>::from_disk(path_str),
+        Err(SnapshotError::NotASnapshot(_))
+    ));
+
+    // Snapshots from before the header was added can still be read
+    std::fs::write(&path, bincode::serialize(&table).unwrap()).unwrap();
+    let (header, loaded) = read_snapshot::<Table<String, Value>>(&path).unwrap();
+    assert!(header.is_none() && loaded == table);
+
+    // As can version 1, which had no compression byte
+    let payload = bincode::serialize(&table).unwrap();
+    let mut v1 = b"RUSTDB".to_vec();
+    v1.extend_from_slice(&1u16.to_le_bytes());
+    v1.extend_from_slice(&0u64.to_le_bytes());
+    v1.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    v1.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    v1.extend_from_slice(&payload);
+    std::fs::write(&path, &v1).unwrap();
+    let (header, loaded) = read_snapshot::<Table<String, Value>>(&path).unwrap();
+    assert!(header.unwrap().version == 1 && loaded == table);
+
+    // Compressed snapshots are smaller, and are decompressed automatically
+    for compression in [Compression::Lz4, Compression::Zstd] {
+        table.to_disk_compressed(path_str, compression).unwrap();
+        let (header, loaded) = read_snapshot::<Table<String, Value>>(&path).unwrap();
+        assert!(header.unwrap().compression == compression && loaded == table);
+        assert!(std::fs::metadata(&path).unwrap().len() < bytes.len() as u64);
+    }
+
+    // An lz4 size prefix that the payload couldn't possibly decompress to is refused up front
+    let mut payload = u32::MAX.to_le_bytes().to_vec();
+    payload.extend_from_slice(&[0; 16]);
+    let header = Header {
+        version: FORMAT_VERSION,
+        compression: Compression::Lz4,
+        created_at: 0,
+        payload_len: payload.len() as u64,
+        checksum: crc32fast::hash(&payload),
+    };
+    std::fs::write(&path, [header.encode().as_slice(), &payload].concat()).unwrap();
+    assert!(matches!(
+        Table::<String, Value>::from_disk(path_str),
+        Err(SnapshotError::Decompression(e)) if e.contains("size prefix")
+    ));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_legacy_snapshot() {
+    use rustdb::hash::SipBuildHasher;
+
+    // Saved by the original non-generic table, with four buckets at level 2. Its entries have no expiry
+    // time and were placed by an FNV hash of the key.
+    const LEGACY: &[u8] = &[
+        4, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+        5, 0, 0, 0, 0, 0, 0, 0, 99, 111, 117, 110, 116, 1, 0, 0,
+        0, 42, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0,
+        0, 4, 0, 0, 0, 0, 0, 0, 0, 108, 105, 115, 116, 2, 0, 0,
+        0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0,
+        0, 116, 119, 111, 2, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0,
+        0, 0, 0, 0, 110, 97, 109, 101, 0, 0, 0, 0, 6, 0, 0, 0,
+        0, 0, 0, 0, 114, 117, 115, 116, 100, 98, 7, 0, 0, 0, 0, 0,
+        0, 0, 110, 111, 116, 104, 105, 110, 103, 3, 0, 0, 0, 1, 0, 0,
+        0, 0, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0, 115, 116, 97,
+        116, 117, 115, 4, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 79,
+        75, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0,
+    ];
+
+    let path = std::env::temp_dir().join(format!("rustdb-test-legacy-{}", std::process::id()));
+    std::fs::write(&path, LEGACY).unwrap();
+
+    let (header, mut table) =
+        Table::<String, Value, SipBuildHasher>::load_keyspace(&path, SipBuildHasher::random()).unwrap();
+    assert!(header.is_none());
+    assert!(table.len() == 5);
+    assert!(table.get("name") == Some(&Value::String("rustdb".to_string())));
+    assert!(table.get("count") == Some(&Value::Int(42)));
+    assert!(table.get("list") == Some(&Value::Array(vec![Value::Int(1), Value::String("two".to_string())])));
+    assert!(table.get("nothing") == Some(&Value::Null));
+    assert!(table.get("status") == Some(&Value::SimpleString("OK".to_string())));
+    // Every entry has been moved to the bucket the new hasher puts it in
+    assert!(table.verify().is_empty());
+
+    // Recovery reads them in the old layout too (which the current one can't), including from a damaged copy
+    type Keyspace = Table<String, Value, SipBuildHasher>;
+    assert!(Keyspace::recover_entries(LEGACY).1.is_some());
+    let (entries, error) = Keyspace::recover_from_payload(LEGACY, true);
+    assert!(entries.len() == 5 && error.is_none());
+    for entry in &entries {
+        assert!(table.get_entry(entry.key()) == Some(entry));
+    }
+    let (entries, error) = Keyspace::recover_from_payload(&LEGACY[..LEGACY.len() - 30], true);
+    assert!(entries.len() == 4 && error.is_some());
+    assert!(entries.iter().all(|entry| table.get_entry(entry.key()) == Some(entry)));
+
+    // Only headerless snapshots are tried in the old layout
+    let mut with_header = b"RUSTDB".to_vec();
+    with_header.extend_from_slice(&1u16.to_le_bytes());
+    with_header.extend_from_slice(&0u64.to_le_bytes());
+    with_header.extend_from_slice(&(LEGACY.len() as u64).to_le_bytes());
+    with_header.extend_from_slice(&crc32fast::hash(LEGACY).to_le_bytes());
+    with_header.extend_from_slice(LEGACY);
+    std::fs::write(&path, &with_header).unwrap();
+    assert!(matches!(
+        Table::<String, Value, SipBuildHasher>::load_keyspace(&path, SipBuildHasher::random()),
+        Err(rustdb::snapshot::SnapshotError::Serialization(_))
+    ));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_persistence_commands() {
+    for command in [
+        Command::Save,
+        Command::BgSave,
+        Command::LastSave,
+        Command::BgRewriteAof,
+        Command::Info(None),
+        Command::Info(Some("persistence".to_string())),
+    ] {
+        assert!(Command::decode_resp(&command.clone().encode_resp()) == Ok(command));
+    }
+}
+
+#[test]
+fn test_export_import() {
+    use rustdb::export::{export, import, value_from_json, value_to_json, Format};
+    use rustdb::table::unix_time_ms;
+
+    let values = vec![
+        Value::String("hello, \"world\"\n".to_string()),
+        Value::String("".to_string()),
+        Value::Int(i64::MIN),
+        Value::Int(i64::MAX),
+        Value::Null,
+        Value::SimpleString("OK".to_string()),
+        Value::SimpleError("ERR nope".to_string()),
+        Value::Bytes(vec![0, 0xff, 0xfe]),
+        Value::Boolean(true),
+        Value::Double(f64::NEG_INFINITY),
+        Value::Double(0.1),
+        Value::BigNumber("-12345678901234567890".to_string()),
+        Value::VerbatimString("txt".to_string(), "a:b".to_string()),
+        Value::Map(vec![(Value::Int(1), Value::String("one".to_string())), (Value::Null, Value::Array(vec![]))]),
+        Value::Set(vec![Value::String("a".to_string())]),
+        Value::Push(vec![Value::String("message".to_string()), Value::Int(1)]),
+        Value::Array(vec![]),
+        Value::Array(vec![
+            Value::Int(1),
+            Value::Array(vec![Value::Null, Value::String("nested".to_string())]),
+            Value::SimpleString("simple".to_string()),
+        ]),
+    ];
+    for value in &values {
+        assert!(value_from_json(&value_to_json(value)).as_ref() == Ok(value));
+    }
+    assert!(value_from_json(&serde_json::json!(1.5)).is_err());
+    assert!(value_from_json(&serde_json::json!({ "something": "else" })).is_err());
+
+    let mut table = Table::new();
+    let later = unix_time_ms() + 3_600_000;
+    for (i, value) in values.iter().enumerate() {
+        let expires_at = if i % 2 == 0 { Some(later) } else { None };
+        table.set_with_expiry(format!("key,{}", i), value.clone(), expires_at);
+    }
+
+    for format in [Format::JsonLines, Format::Csv] {
+        let mut text = Vec::new();
+        assert!(export(&table, format, &mut text) == Ok(values.len()));
+
+        let mut imported = Table::new();
+        assert!(import(&mut imported, format, text.as_slice()) == Ok(values.len()));
+        for (i, value) in values.iter().enumerate() {
+            let entry = imported.get_entry(&format!("key,{}", i)).unwrap();
+            assert!(entry.value() == value);
+            assert!(entry.expires_at() == if i % 2 == 0 { Some(later) } else { None });
+        }
+    }
+
+    // Nothing is imported if any line is bad
+    let mut imported = Table::<String, Value>::new();
+    let text = "{\"key\":\"a\",\"value\":1}\n{\"key\":\"b\",\"value\":{\"map\":[1]}}\n";
+    assert!(import(&mut imported, Format::JsonLines, text.as_bytes()) == Err("line 2: not a key-value pair: 1".to_string()));
+    assert!(imported.is_empty());
+}
+
+#[test]
+fn test_verify_and_recover() {
+    use rustdb::hash::SipBuildHasher;
+    use rustdb::snapshot::{salvage_payload, Compression};
+
+    let mut table = Table::with_hasher(SipBuildHasher::new(1, 2));
+    for i in 0..1000 {
+        table.set(format!("key/{}", i), Value::Int(i));
+    }
+    assert!(table.verify().is_empty());
+
+    // Changing the hasher's seed (the last field) puts nearly every entry in the wrong bucket
+    let mut bytes = bincode::serialize(&table).unwrap();
+    let len = bytes.len();
+    bytes[len - 1] ^= 0xff;
+    let reseeded: Table<String, Value, SipBuildHasher> = bincode::deserialize(&bytes).unwrap();
+    assert!(reseeded.verify().len() > 900);
+
+    // Every entry can be recovered from an intact payload...
+    let (entries, error) = Table::<String, Value, SipBuildHasher>::recover_entries(&bytes);
+    assert!(entries.len() == 1000 && error.is_none());
+
+    // ...and the ones before the damage from a truncated one
+    let (entries, error) = Table::<String, Value, SipBuildHasher>::recover_entries(&bytes[..len / 2]);
+    assert!(entries.len() > 400 && entries.len() < 600 && error.is_some());
+    for entry in entries {
+        assert!(table.get_entry(entry.key()) == Some(&entry));
+    }
+
+    // The header is skipped even if its magic is damaged, for both versions
+    let path = std::env::temp_dir().join(format!("rustdb-test-salvage-{}", std::process::id()));
+    table.to_disk_compressed(path.to_str().unwrap(), Compression::Zstd).unwrap();
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[..3].copy_from_slice(b"XYZ");
+    std::fs::write(&path, &bytes).unwrap();
+    let (header, payload) = salvage_payload(&path).unwrap();
+    assert!(header.unwrap().compression == Compression::Zstd);
+    let (entries, error) = Table::<String, Value, SipBuildHasher>::recover_entries(&payload);
+    assert!(entries.len() == 1000 && error.is_none());
+
+    let payload = bincode::serialize(&table).unwrap();
+    let mut v1 = b"RUSTDX".to_vec();
+    v1.extend_from_slice(&1u16.to_le_bytes());
+    v1.extend_from_slice(&0u64.to_le_bytes());
+    v1.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    v1.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    v1.extend_from_slice(&payload);
+    std::fs::write(&path, &v1).unwrap();
+    let (header, salvaged) = salvage_payload(&path).unwrap();
+    assert!(header.unwrap().version == 1 && salvaged == payload);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_binary_values() {
+    use rustdb::value::{bulk_value, escape_bytes};
+
+    let bytes = vec![0, b'\r', b'\n', 0xff, b'"', b'\\'];
+    let value = Value::Bytes(bytes.clone());
+    assert!(value.clone().encode_resp() == [b"$6\r\n".as_slice(), &bytes, b"\r\n"].concat());
+    assert!(Value::decode_resp(&value.clone().encode_resp()) == Ok(value.clone()));
+
+    // Multi-byte characters count as several bytes towards a bulk string's length
+    let s = Value::String("héllo".to_string());
+    assert!(s.clone().encode_resp() == "$6\r\nhéllo\r\n".as_bytes());
+    assert!(Value::decode_resp(&s.clone().encode_resp()) == Ok(s));
+
+    let command = Command::Set("binary".to_string(), value.clone(), None);
+    assert!(Command::decode_resp(&command.clone().encode_resp()) == Ok(command));
+
+    assert!(bulk_value(b"text".to_vec()) == Value::String("text".to_string()));
+    assert!(bulk_value(bytes.clone()) == value);
+    assert!(escape_bytes(&bytes) == "\\x00\\r\\n\\xff\\\"\\\\");
+
+    // Binary values survive a snapshot
+    let path = std::env::temp_dir().join(format!("rustdb-test-binary-{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    let mut table = Table::new();
+    table.set("binary".to_string(), value.clone());
+    table.to_disk(path).unwrap();
+    let mut loaded: Table<String, Value> = Table::from_disk(path).unwrap();
+    assert!(loaded.get(&"binary".to_string()) == Some(&value));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_resp_golden() {
+    // Frames as sent by redis-cli and real Redis servers, and the examples from the RESP2 spec
+    let values = vec![
+        (b"+OK\r\n".as_slice(), Value::SimpleString("OK".to_string())),
+        (b"-ERR unknown command 'foobar'\r\n", Value::SimpleError("ERR unknown command 'foobar'".to_string())),
+        (b":1000\r\n", Value::Int(1000)),
+        (b":-1\r\n", Value::Int(-1)),
+        (b"$5\r\nhello\r\n", Value::String("hello".to_string())),
+        (b"$0\r\n\r\n", Value::String("".to_string())),
+        (b"$-1\r\n", Value::Null),
+        (b"*0\r\n", Value::Array(vec![])),
+        (
+            b"*2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n",
+            Value::Array(vec![Value::String("foo".to_string()), Value::String("bar".to_string())]),
+        ),
+        (
+            b"*3\r\n$5\r\nhello\r\n$-1\r\n$5\r\nworld\r\n",
+            Value::Array(vec![Value::String("hello".to_string()), Value::Null, Value::String("world".to_string())]),
+        ),
+        (
+            b"*2\r\n*3\r\n:1\r\n:2\r\n:3\r\n*2\r\n+Foo\r\n-Bar\r\n",
+            Value::Array(vec![
+                Value::Array(vec![Value::Int(1), Value::Int(2), Value::Int(3)]),
+                Value::Array(vec![Value::SimpleString("Foo".to_string()), Value::SimpleError("Bar".to_string())]),
+            ]),
+        ),
+    ];
+    for (frame, value) in values {
+        assert!(Value::decode_resp(frame).as_ref() == Ok(&value));
+        assert!(value.encode_resp() == frame);
+    }
+
+    // A null array is decoded, but nil is always sent as a null bulk string
+    assert!(Value::decode_resp(b"*-1\r\n") == Ok(Value::Null));
+
+    let commands = vec![
+        (b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n".as_slice(), Command::Get("foo".to_string())),
+        (
+            b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n",
+            Command::Set("foo".to_string(), Value::String("bar".to_string()), None),
+        ),
+        (
+            b"*5\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n$2\r\nPX\r\n$3\r\n500\r\n",
+            Command::Set("foo".to_string(), Value::String("bar".to_string()), Some(500)),
+        ),
+        (b"*3\r\n$6\r\nEXPIRE\r\n$3\r\nfoo\r\n$2\r\n10\r\n", Command::Expire("foo".to_string(), 10)),
+        (b"*1\r\n$4\r\nSAVE\r\n", Command::Save),
+    ];
+    for (frame, command) in commands {
+        assert!(Command::decode_resp(frame).as_ref() == Ok(&command));
+        assert!(command.encode_resp() == frame);
+    }
+
+    // The old encoding, with a CRLF after every array, isn't valid RESP
+    assert!(Value::decode_resp(b"*0\r\n\r\n").is_err());
+    assert!(Value::decode_resp(b"*1:1\r\n\r\n").is_err());
+}
+
+#[test]
+fn test_resp3() {
+    use rustdb::resp::Protocol;
+
+    // Examples from the RESP3 spec
+    let values = vec![
+        (b"_\r\n".as_slice(), Value::Null),
+        (b"#t\r\n", Value::Boolean(true)),
+        (b"#f\r\n", Value::Boolean(false)),
+        (b",1.23\r\n", Value::Double(1.23)),
+        (b",10\r\n", Value::Double(10.0)),
+        (b",inf\r\n", Value::Double(f64::INFINITY)),
+        (b",-inf\r\n", Value::Double(f64::NEG_INFINITY)),
+        (b"(3492890328409238509324850943850943825024385\r\n", Value::BigNumber("3492890328409238509324850943850943825024385".to_string())),
+        (b"=15\r\ntxt:Some string\r\n", Value::VerbatimString("txt".to_string(), "Some string".to_string())),
+        (
+            b"%2\r\n+first\r\n:1\r\n+second\r\n:2\r\n",
+            Value::Map(vec![
+                (Value::SimpleString("first".to_string()), Value::Int(1)),
+                (Value::SimpleString("second".to_string()), Value::Int(2)),
+            ]),
+        ),
+        (b"~2\r\n+orange\r\n+apple\r\n", Value::Set(vec![Value::SimpleString("orange".to_string()), Value::SimpleString("apple".to_string())])),
+        (
+            b">3\r\n$7\r\nmessage\r\n$7\r\nchannel\r\n$5\r\nhello\r\n",
+            Value::Push(vec![
+                Value::String("message".to_string()),
+                Value::String("channel".to_string()),
+                Value::String("hello".to_string()),
+            ]),
+        ),
+        (b"*2\r\n_\r\n#t\r\n", Value::Array(vec![Value::Null, Value::Boolean(true)])),
+    ];
+    for (frame, value) in values {
+        assert!(Value::decode_resp(frame).as_ref() == Ok(&value));
+        assert!(value.encode(Protocol::Resp3) == frame);
+    }
+    assert!(matches!(Value::decode_resp(b",nan\r\n"), Ok(Value::Double(d)) if d.is_nan()));
+    assert!(Value::Double(f64::NAN).encode(Protocol::Resp3) == b",nan\r\n");
+
+    // RESP2 connections get the closest RESP2 types instead
+    let downgraded = vec![
+        (Value::Null, b"$-1\r\n".as_slice()),
+        (Value::Boolean(true), b":1\r\n"),
+        (Value::Double(1.5), b"$3\r\n1.5\r\n"),
+        (Value::BigNumber("123".to_string()), b"$3\r\n123\r\n"),
+        (Value::VerbatimString("txt".to_string(), "hi".to_string()), b"$2\r\nhi\r\n"),
+        (Value::Map(vec![(Value::String("a".to_string()), Value::Int(1))]), b"*2\r\n$1\r\na\r\n:1\r\n"),
+        (Value::Set(vec![Value::Int(1)]), b"*1\r\n:1\r\n"),
+        (Value::Push(vec![Value::Null]), b"*1\r\n$-1\r\n"),
+    ];
+    for (value, frame) in downgraded {
+        assert!(value.clone().encode(Protocol::Resp2) == frame);
+        assert!(value.encode_resp() == frame);
+    }
+
+    assert!(Value::decode_resp(b"#x\r\n").is_err());
+    assert!(Value::decode_resp(b"(12a\r\n").is_err());
+    assert!(Value::decode_resp(b"=3\r\ntxt\r\n").is_err());
+    assert!(Value::decode_resp(b"%1\r\n:1\r\n").is_err());
+
+    let hello = Command::Hello(Some(3));
+    assert!(Command::decode_resp(&hello.clone().encode_resp()) == Ok(hello));
+}
+
+#[test]
+fn test_streaming_decoder() {
+    use bytes::BytesMut;
+    use rustdb::codec::RespCodec;
+    use rustdb::resp::{Protocol, MAX_BULK_LEN, MAX_INLINE_LEN};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    let frame = Command::Set("key".to_string(), Value::String("héllo".to_string()), None).encode_resp();
+    let expected = Value::decode_resp(&frame).unwrap();
+
+    // Fed a byte at a time, nothing comes out until the frame is complete
+    let mut codec = RespCodec::new();
+    let mut buffer = BytesMut::new();
+    for (i, byte) in frame.iter().enumerate() {
+        buffer.extend_from_slice(&[*byte]);
+        let decoded = codec.decode(&mut buffer).unwrap();
+        if i + 1 < frame.len() {
+            assert!(decoded.is_none());
+        } else {
+            assert!(decoded.as_ref() == Some(&expected));
+        }
+    }
+    assert!(buffer.is_empty());
+
+    // Several frames in one buffer come out one at a time, leaving any partial frame behind
+    let mut buffer = BytesMut::from([frame.as_slice(), b":1\r\n+OK\r\n", &frame[..5]].concat().as_slice());
+    assert!(codec.decode(&mut buffer).unwrap() == Some(expected));
+    assert!(codec.decode(&mut buffer).unwrap() == Some(Value::Int(1)));
+    assert!(codec.decode(&mut buffer).unwrap() == Some(Value::SimpleString("OK".to_string())));
+    assert!(codec.decode(&mut buffer).unwrap().is_none());
+    assert!(buffer.as_ref() == &frame[..5]);
+
+    // A big frame arriving in small pieces is checked where the last piece left off rather than parsed
+    // from the start every time, which would take quadratic time
+    let big = Value::Array(
+        (0..20_000)
+            .map(|i| match i % 5 {
+                0 => Value::Int(i),
+                1 => Value::String(format!("value {}", i)),
+                2 => Value::Array(vec![Value::Null, Value::Array(vec![]), Value::SimpleString("OK".to_string())]),
+                3 => Value::Map(vec![(Value::String("k".to_string()), Value::Set(vec![Value::Boolean(true)]))]),
+                _ => Value::Push(vec![Value::Double(1.5), Value::VerbatimString("txt".to_string(), "x".to_string())]),
+            })
+            .collect(),
+    );
+    let frame = big.clone().encode(Protocol::Resp3);
+    let mut buffer = BytesMut::new();
+    let mut decoded = None;
+    for (i, chunk) in frame.chunks(7).enumerate() {
+        assert!(decoded.is_none());
+        buffer.extend_from_slice(chunk);
+        decoded = codec.decode(&mut buffer).unwrap();
+        assert!(decoded.is_some() == ((i + 1) * 7 >= frame.len()));
+    }
+    assert!(decoded == Some(big) && buffer.is_empty());
+
+    // Garbage is an error
+    assert!(codec.decode(&mut BytesMut::from("?what\r\n")).is_err());
+
+    // So are lengths over the limit, as soon as they are seen
+    let too_long = format!("${}\r\n", MAX_BULK_LEN + 1);
+    assert!(codec.decode(&mut BytesMut::from(too_long.as_str())).is_err());
+    assert!(codec.decode(&mut BytesMut::from(format!("${}\r\n", MAX_BULK_LEN).as_str())).unwrap().is_none());
+    assert!(codec.decode(&mut BytesMut::from("*99999999999\r\n")).is_err());
+
+    // And frames that outgrow the buffer limit, complete or not
+    let mut small = RespCodec::new();
+    small.max_frame_len = 16;
+    assert!(small.decode(&mut BytesMut::from("$100\r\n0123456789012345")).is_err());
+    assert!(small.decode(&mut BytesMut::from("$12\r\n0123456789ab\r\n")).is_err());
+    assert!(small.decode(&mut BytesMut::from("$4\r\nabcd\r\n")).unwrap().is_some());
+
+    // Inline commands, for the server side
+    let mut server = RespCodec::new();
+    server.inline = true;
+    let mut buffer = BytesMut::from("PING\r\nGET  key\nSET k");
+    assert!(server.decode(&mut buffer).unwrap() == Some(Value::Array(vec![Value::String("PING".to_string())])));
+    assert!(
+        server.decode(&mut buffer).unwrap()
+            == Some(Value::Array(vec![Value::String("GET".to_string()), Value::String("key".to_string())]))
+    );
+    assert!(server.decode(&mut buffer).unwrap().is_none());
+
+    // Arguments can be quoted like in redis-cli
+    let args = |line: &str| match server.clone().decode(&mut BytesMut::from(line)) {
+        Ok(Some(Value::Array(args))) => Ok(args),
+        Ok(other) => panic!("unexpected {:?}", other),
+        Err(e) => Err(e.to_string()),
+    };
+    let strings = |args: &[&str]| args.iter().map(|arg| Value::String(arg.to_string())).collect::<Vec<_>>();
+    assert!(args("SET k \"hello world\"\r\n") == Ok(strings(&["SET", "k", "hello world"])));
+    assert!(args("SET k 'it''s'\n").is_err());
+    assert!(args("SET k 'it\\'s \\n'\n") == Ok(strings(&["SET", "k", "it's \\n"])));
+    assert!(args("SET k \"\\x41\\tb\\\"\\\\\" \"\"\n") == Ok(strings(&["SET", "k", "A\tb\"\\", ""])));
+    let binary = args("SET k \"\\xff\"\n").unwrap();
+    assert!(binary[2] == Value::Bytes(vec![0xff]));
+    assert!(args("SET k \"unterminated\n") == Err("Protocol error: unbalanced quotes in request".to_string()));
+    assert!(args("SET k \"a\"b\n") == Err("Protocol error: unbalanced quotes in request".to_string()));
+
+    // Inline commands are limited to 64 KiB, whether or not the end of the line has arrived
+    let long = "x".repeat(MAX_INLINE_LEN - 4);
+    assert!(server.clone().decode(&mut BytesMut::from(format!("GET {}", long).as_str())).unwrap().is_none());
+    assert!(args(&format!("GET {}\n", long)).is_ok());
+    assert!(args(&format!("GET {}x", long)) == Err("Protocol error: too big inline request".to_string()));
+    assert!(args(&format!("GET {}x\n", long)) == Err("Protocol error: too big inline request".to_string()));
+
+    let mut encoded = BytesMut::new();
+    server.protocol = Protocol::Resp3;
+    server.encode(Value::Null, &mut encoded).unwrap();
+    assert!(encoded.as_ref() == b"_\r\n");
+}
+
+#[test]
+fn test_errors() {
+    use rustdb::Error;
+
+    let command = |args: &[&str]| {
+        Command::from_value(Value::Array(args.iter().map(|arg| Value::String(arg.to_string())).collect()))
+    };
+    assert!(command(&["GET"]) == Err(Error::WrongArity("GET".to_string())));
+    assert!(command(&["GET", "a", "b"]) == Err(Error::WrongArity("GET".to_string())));
+    assert!(command(&["expire", "k"]) == Err(Error::WrongArity("EXPIRE".to_string())));
+    assert!(command(&["nope"]) == Err(Error::UnknownCommand("nope".to_string())));
+    assert!(matches!(command(&["EXPIRE", "k", "soon"]), Err(Error::InvalidArgument(_))));
+    assert!(matches!(command(&["SET", "k", "v", "XX", "1"]), Err(Error::Syntax(_))));
+    assert!(matches!(Command::from_value(Value::Int(1)), Err(Error::Protocol(_))));
+    assert!(matches!(Command::decode_resp(b"*1\r\n"), Err(Error::Protocol(_))));
+
+    // Each kind of error goes to clients with the prefix Redis would use
+    let reply = |e: Error| Value::from(e);
+    assert!(reply(Error::UnknownCommand("nope".to_string())) == Value::SimpleError("ERR unknown command 'nope'".to_string()));
+    assert!(
+        reply(Error::WrongArity("GET".to_string()))
+            == Value::SimpleError("ERR wrong number of arguments for 'get' command".to_string())
+    );
+    assert!(
+        reply(Error::Protocol("invalid frame".to_string()))
+            == Value::SimpleError("ERR Protocol error: invalid frame".to_string())
+    );
+    assert!(
+        reply(Error::WrongType)
+            == Value::SimpleError("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+    );
+    assert!(
+        reply(Error::OutOfMemory)
+            == Value::SimpleError("OOM command not allowed when used memory > 'maxmemory'".to_string())
+    );
+    assert!(reply(Error::NoProto) == Value::SimpleError("NOPROTO unsupported protocol version".to_string()));
+    let io = Error::from(std::io::Error::other("disk full"));
+    assert!(io.prefix() == "ERR");
+    assert!(std::error::Error::source(&io).is_some());
+}
+
+#[test]
+fn test_malformed_commands() {
+    use rustdb::resp::MAX_NESTING;
+    use rustdb::Error;
+
+    // Missing, extra and wrongly typed arguments are errors
+    assert!(Command::decode_resp(b"*1\r\n$3\r\nGET\r\n") == Err(Error::WrongArity("GET".to_string())));
+    assert!(Command::decode_resp(b"*1\r\n$3\r\nGET\r\n\r\n").is_err());
+    assert!(matches!(Command::decode_resp(b"*2\r\n$3\r\nDEL\r\n*0\r\n"), Err(Error::InvalidArgument(_))));
+    assert!(matches!(Command::decode_resp(b"*2\r\n$3\r\nTTL\r\n$2\r\n\xff\xfe\r\n"), Err(Error::InvalidArgument(_))));
+    assert!(Command::decode_resp(b"*2\r\n$3\r\nGET\r\n:5\r\n") == Ok(Command::Get("5".to_string())));
+    assert!(matches!(Command::decode_resp(b"*1\r\n:5\r\n"), Err(Error::Protocol(_))));
+
+    // Aggregates can only be nested so deep, so that parsing can't overflow the stack
+    let nested = |depth: usize| [b"*1\r\n".repeat(depth), b":1\r\n".to_vec()].concat();
+    assert!(Value::decode_resp(&nested(MAX_NESTING)).is_ok());
+    assert!(Value::decode_resp(&nested(MAX_NESTING + 1)).is_err());
+    assert!(Value::decode_resp(&nested(1_000_000)).is_err());
+
+    // Line breaks can't get into a simple string or error, whatever it was made from
+    assert!(Value::SimpleError("ERR bad\r\n+OK".to_string()).encode_resp() == b"-ERR bad  +OK\r\n");
+    assert!(Value::SimpleString("a\nb".to_string()).encode_resp() == b"+a b\r\n");
+
+    // Non-string values can be displayed
+    assert!(Value::Int(5).to_string() == Value::Int(5).string_repr());
+    assert!(Value::Null.to_string() == "(nil)");
+}
+
+#[test]
+fn test_command_table() {
+    use rustdb::Error;
+
+    // Names and usage come from the variants, in the order they are declared
+    assert!(Command::NAMES.len() == Command::USAGE.len());
+    assert!(Command::NAMES[..3] == ["GET", "SET", "INC"]);
+    assert!(Command::NAMES.contains(&"PEXPIREAT") && Command::NAMES.contains(&"BGREWRITEAOF"));
+    assert!(Command::usage("pexpire") == Some("PEXPIRE <key> <milliseconds>"));
+    assert!(Command::usage("nope").is_none());
+
+    // Arity is checked from the fields, with `Option` fields being optional
+    let command = |args: &[&str]| {
+        Command::from_value(Value::Array(args.iter().map(|arg| Value::String(arg.to_string())).collect()))
+    };
+    assert!(command(&["PING"]) == Ok(Command::Ping(None)));
+    assert!(command(&["PING", "hi"]) == Ok(Command::Ping(Some("hi".to_string()))));
+    assert!(command(&["PING", "a", "b"]) == Err(Error::WrongArity("PING".to_string())));
+    assert!(command(&["PExpireAt", "k", "5"]) == Ok(Command::PExpireAt("k".to_string(), 5)));
+    assert!(command(&["PEXPIREAT", "k"]) == Err(Error::WrongArity("PEXPIREAT".to_string())));
+    assert!(command(&["LASTSAVE"]) == Ok(Command::LastSave));
+    assert!(command(&["LASTSAVE", "now"]) == Err(Error::WrongArity("LASTSAVE".to_string())));
+
+    // Commands handled by the client are never decoded
+    assert!(command(&["HELP"]) == Err(Error::UnknownCommand("HELP".to_string())));
+    assert!(command(&["exit"]) == Err(Error::UnknownCommand("exit".to_string())));
+}
+
+#[cfg(test)]
+fn arbitrary_value(protocol: rustdb::resp::Protocol) -> impl proptest::strategy::Strategy<Value = Value> {
+    use proptest::prelude::*;
+    use rustdb::resp::Protocol;
+    use rustdb::value::bulk_value;
+
+    let resp2 = prop_oneof![
+        any::<String>().prop_map(Value::String),
+        // Mostly invalid UTF-8, which stays as bytes
+        any::<Vec<u8>>().prop_map(bulk_value),
+        any::<i64>().prop_map(Value::Int),
+        Just(Value::Null),
+        // Simple strings and errors can't contain line breaks
+        "[^\r\n]*".prop_map(Value::SimpleString),
+        "[^\r\n]*".prop_map(Value::SimpleError),
+    ];
+    let leaf = match protocol {
+        Protocol::Resp2 => resp2.boxed(),
+        Protocol::Resp3 => prop_oneof![
+            resp2,
+            any::<bool>().prop_map(Value::Boolean),
+            // NaN can't round trip, since it isn't equal to itself
+            any::<f64>().prop_filter("NaN", |d| !d.is_nan()).prop_map(Value::Double),
+            "[+-]?[0-9]{1,50}".prop_map(Value::BigNumber),
+            ("[a-z]{3}", any::<String>()).prop_map(|(format, text)| Value::VerbatimString(format, text)),
+        ]
+        .boxed(),
+    };
+    leaf.prop_recursive(3, 64, 8, move |inner| match protocol {
+        Protocol::Resp2 => proptest::collection::vec(inner, 0..8).prop_map(Value::Array).boxed(),
+        Protocol::Resp3 => prop_oneof![
+            proptest::collection::vec(inner.clone(), 0..8).prop_map(Value::Array),
+            proptest::collection::vec((inner.clone(), inner.clone()), 0..8).prop_map(Value::Map),
+            proptest::collection::vec(inner.clone(), 0..8).prop_map(Value::Set),
+            proptest::collection::vec(inner, 0..8).prop_map(Value::Push),
+        ]
+        .boxed(),
+    })
+}
+
+#[cfg(test)]
+fn arbitrary_command() -> impl proptest::strategy::Strategy<Value = Command> {
+    use proptest::option;
+    use proptest::prelude::*;
+    use rustdb::resp::Protocol;
+
+    let key = any::<String>;
+    prop_oneof![
+        key().prop_map(Command::Get),
+        (key(), arbitrary_value(Protocol::Resp2), option::of(1..u64::MAX))
+            .prop_map(|(key, value, expiry)| Command::Set(key, value, expiry)),
+        key().prop_map(Command::Inc),
+        key().prop_map(Command::Dec),
+        key().prop_map(Command::Del),
+        (any::<u64>(), option::of(any::<String>()), option::of(1..usize::MAX))
+            .prop_map(|(cursor, pattern, count)| Command::Scan(cursor, pattern, count)),
+        (key(), any::<i64>()).prop_map(|(key, seconds)| Command::Expire(key, seconds)),
+        (key(), any::<i64>()).prop_map(|(key, ms)| Command::PExpire(key, ms)),
+        (key(), any::<u64>()).prop_map(|(key, timestamp)| Command::PExpireAt(key, timestamp)),
+        key().prop_map(Command::Ttl),
+        key().prop_map(Command::PTtl),
+        key().prop_map(Command::Persist),
+        option::of(any::<String>()).prop_map(Command::Info),
+        option::of(any::<String>()).prop_map(Command::Ping),
+        option::of(any::<u64>()).prop_map(Command::Hello),
+        Just(Command::Save),
+        Just(Command::BgSave),
+        Just(Command::LastSave),
+        Just(Command::BgRewriteAof),
+    ]
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_value_round_trip(value in arbitrary_value(rustdb::resp::Protocol::Resp2)) {
+        proptest::prop_assert_eq!(Value::decode_resp(&value.clone().encode_resp()), Ok(value));
+    }
+
+    #[test]
+    fn test_resp3_value_round_trip(value in arbitrary_value(rustdb::resp::Protocol::Resp3)) {
+        let encoded = value.clone().encode(rustdb::resp::Protocol::Resp3);
+        proptest::prop_assert_eq!(Value::decode_resp(&encoded), Ok(value));
+    }
+
+    #[test]
+    fn test_command_round_trip(command in arbitrary_command()) {
+        proptest::prop_assert_eq!(Command::decode_resp(&command.clone().encode_resp()), Ok(command));
+    }
+
+    // Whatever a client sends, decoding it gives an error rather than a panic. The fuzz target in
+    // fuzz/ does the same thing more thoroughly.
+    #[test]
+    fn test_malformed_input(
+        bytes in proptest::collection::vec(proptest::prelude::any::<u8>(), 0..64),
+        prefix in proptest::sample::select(vec!["", "*2\r\n", "*3\r\n$3\r\nSET\r\n", "%1\r\n", "*1\r\n$4\r\nPING\r\n"]),
+    ) {
+        let input = [prefix.as_bytes(), &bytes].concat();
+        if let Ok(value) = Value::decode_resp(&input) {
+            let _ = value.to_string();
+        }
+        let _ = Command::decode_resp(&input);
+    }
+
+    // Bulk string lengths are in bytes, however many characters the string has
+    #[test]
+    fn test_bulk_length(s in proptest::prelude::any::<String>()) {
+        let encoded = Value::String(s.clone()).encode_resp();
+        let prefix = format!("${}\r\n", s.len());
+        proptest::prop_assert!(encoded.starts_with(prefix.as_bytes()));
+    }
+}
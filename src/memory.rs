@@ -0,0 +1,59 @@
+use std::mem::size_of;
+
+// Approximate memory accounting. Tables that track their memory (see `Table::track_memory`) use this to
+// keep a running total of how much memory their entries take up, which the server compares against its
+// memory limit.
+pub trait MemoryUsage {
+    // The number of bytes owned on the heap, not counting the value itself (see `size_of`).
+    fn heap_size(&self) -> usize;
+
+    // The total number of bytes taken up by the value.
+    fn memory_usage(&self) -> usize
+    where
+        Self: Sized,
+    {
+        size_of::<Self>() + self.heap_size()
+    }
+}
+
+macro_rules! impl_memory_usage_inline {
+    ($($t:ty),*) => {
+        $(impl MemoryUsage for $t {
+            fn heap_size(&self) -> usize {
+                0
+            }
+        })*
+    };
+}
+
+impl_memory_usage_inline!(bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, ());
+
+impl MemoryUsage for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl<T: MemoryUsage> MemoryUsage for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * size_of::<T>() + self.iter().map(T::heap_size).sum::<usize>()
+    }
+}
+
+impl<T: MemoryUsage> MemoryUsage for Box<T> {
+    fn heap_size(&self) -> usize {
+        size_of::<T>() + (**self).heap_size()
+    }
+}
+
+impl<T: MemoryUsage> MemoryUsage for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, T::heap_size)
+    }
+}
+
+impl<A: MemoryUsage, B: MemoryUsage> MemoryUsage for (A, B) {
+    fn heap_size(&self) -> usize {
+        self.0.heap_size() + self.1.heap_size()
+    }
+}
@@ -1,25 +1,108 @@
 use nom::bytes::complete::{tag, take, take_until};
 use nom::character::complete::u64;
+use nom::combinator::map_res;
+use nom::error::ErrorKind;
 use nom::sequence::tuple;
 use nom::IResult;
+use tokio_websockets::Message;
 
+use crate::error::Error;
+
+// The protocol version a connection speaks, chosen by the client with HELLO. Connections start out on
+// RESP2, which every client understands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+impl Protocol {
+    pub fn from_version(version: u64) -> Option<Self> {
+        match version {
+            2 => Some(Self::Resp2),
+            3 => Some(Self::Resp3),
+            _ => None,
+        }
+    }
+
+    pub fn version(self) -> u64 {
+        match self {
+            Self::Resp2 => 2,
+            Self::Resp3 => 3,
+        }
+    }
+}
+
+// RESP is binary-safe, so frames are bytes rather than strings
 pub trait RESP {
-    fn encode_resp(self) -> String;
-    fn decode_resp(s: String) -> Result<Self, String>
+    fn encode_resp(self) -> Vec<u8>;
+    fn decode_resp(bytes: &[u8]) -> Result<Self, Error>
     where
         Self: Sized;
 }
 
-pub fn parse_bulk<'a>(input: &'a str, prefix: &'a str) -> IResult<&'a str, String> {
+// The longest bulk string accepted, the same as Redis's default proto-max-bulk-len
+pub const MAX_BULK_LEN: u64 = 512 * 1024 * 1024;
+
+// The most elements accepted in an array (or other aggregate), the same as Redis
+pub const MAX_AGGREGATE_LEN: u64 = i32::MAX as u64;
+
+// The longest inline command accepted, the same as Redis's PROTO_INLINE_MAX_SIZE
+pub const MAX_INLINE_LEN: usize = 64 * 1024;
+
+// How deeply aggregates can be nested in one another. Parsing recurses for each level, so without a
+// limit a frame like "*1\r\n*1\r\n..." could overflow the stack. Real replies are nowhere near this deep.
+pub const MAX_NESTING: usize = 64;
+
+// Rejects a declared length over the limit outright, rather than waiting for that much input to arrive
+pub fn check_length(input: &[u8], len: u64, max: u64) -> Result<(), nom::Err<nom::error::Error<&[u8]>>> {
+    if len > max {
+        return Err(nom::Err::Failure(nom::error::Error::new(input, ErrorKind::TooLarge)));
+    }
+    Ok(())
+}
+
+// Rejects an aggregate nested too deeply, reported with ErrorKind::Count to tell it apart from a bad length
+pub fn check_nesting(input: &[u8], depth: usize) -> Result<(), nom::Err<nom::error::Error<&[u8]>>> {
+    if depth > MAX_NESTING {
+        return Err(nom::Err::Failure(nom::error::Error::new(input, ErrorKind::Count)));
+    }
+    Ok(())
+}
+
+pub fn parse_bulk<'a>(input: &'a [u8], prefix: &'a str) -> IResult<&'a [u8], Vec<u8>> {
     let (remaining, (_, len, _)) = tuple((tag(prefix), u64, tag("\r\n")))(input)?;
+    check_length(input, len, MAX_BULK_LEN)?;
 
     let (remaining, (s, _)) = tuple((take(len), tag("\r\n")))(remaining)?;
-    let s = s.to_string();
-
-    Ok((remaining, s))
+    Ok((remaining, s.to_vec()))
 }
 
-pub fn parse_simple<'a>(input: &'a str, prefix: &'a str) -> IResult<&'a str, String> {
-    let (remaining, (_, s, _)) = tuple((tag(prefix), take_until("\r\n"), tag("\r\n")))(input)?;
+// Simple strings can't contain CR or LF, and have to be valid UTF-8
+pub fn parse_simple<'a>(input: &'a [u8], prefix: &'a str) -> IResult<&'a [u8], String> {
+    let (remaining, (_, s, _)) = tuple((
+        tag(prefix),
+        map_res(take_until("\r\n"), std::str::from_utf8),
+        tag("\r\n"),
+    ))(input)?;
     Ok((remaining, s.to_string()))
 }
+
+// Whether a parse error was caused by running out of input (i.e. the frame was cut off part of the
+// way through) rather than by garbage, in which case more input might make it parse.
+pub fn is_incomplete(error: &nom::error::Error<&[u8]>) -> bool {
+    matches!(error.code, ErrorKind::Eof | ErrorKind::TakeUntil)
+        || b"\r\n".starts_with(error.input)
+        || b"$-1\r\n".starts_with(error.input)
+        || b"*-1\r\n".starts_with(error.input)
+}
+
+// Wraps an encoded frame in a WebSocket message. Text messages have to be valid UTF-8, so anything
+// else (i.e. a frame with binary data in it) is sent as a binary message.
+pub fn websocket_message(frame: Vec<u8>) -> Message {
+    match String::from_utf8(frame) {
+        Ok(text) => Message::text(text),
+        Err(e) => Message::binary(e.into_bytes()),
+    }
+}
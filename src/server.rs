@@ -1,15 +1,25 @@
 use futures_util::{SinkExt, StreamExt};
 use std::sync::{Arc, Mutex};
-use tokio;
-use tokio::net::TcpListener;
-use tokio_websockets::{Error, Message, ServerBuilder};
-use clap::{command, Parser};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::Decoder;
+use bytes::BytesMut;
+use tokio_websockets::ServerBuilder;
+use clap::Parser;
+use std::fs;
 use std::path::Path;
 
+use rustdb::aof::{AppendOnlyFile, FsyncPolicy};
+use rustdb::codec::RespCodec;
 use rustdb::command::Command;
-use rustdb::resp::RESP;
-use rustdb::table::Table;
-use rustdb::value::Value;
+use rustdb::error::Error;
+use rustdb::glob::glob_match;
+use rustdb::hash::SipBuildHasher;
+use rustdb::resp::{websocket_message, Protocol};
+use rustdb::snapshot::{write_snapshot, Compression, SaveSchedule, FORMAT_VERSION};
+use rustdb::table::{unix_time_ms, Entry, EvictionPolicy, Table};
+use rustdb::value::{escape_bytes, Value};
 
 #[derive(Parser)]
 #[command(name = "rustdb")]
@@ -20,112 +30,740 @@ struct Cli {
     path: String,
     #[arg(long, default_value_t = 3000)]
     port: u16,
+    /// Also listen for plain RESP connections (e.g. from redis-cli) on this port
+    #[arg(long)]
+    resp_port: Option<u16>,
+    /// Save the database to disk automatically, according to --save. This is the default.
     #[arg(long, default_value_t = true)]
     autosave: bool,
+    /// Only save the database when asked to, with SAVE or BGSAVE
+    #[arg(long, conflicts_with = "autosave")]
+    no_autosave: bool,
+    /// When to save automatically, as pairs of <seconds> <changes>: e.g. "3600 1 300 100" saves after an
+    /// hour if anything changed, or after 5 minutes if at least 100 keys changed
+    #[arg(long, default_value = "3600 1 300 100 60 10000")]
+    save: SaveSchedule,
+    /// The memory limit, e.g. 512mb or 2gb. 0 means no limit.
+    #[arg(long, default_value = "0", value_parser = parse_memory_size)]
+    maxmemory: usize,
+    /// What to evict once the memory limit is reached: noeviction, allkeys-lru, allkeys-lfu, volatile-ttl or allkeys-random
+    #[arg(long, default_value = "noeviction")]
+    maxmemory_policy: EvictionPolicy,
+    /// Log every write to this append-only file, and rebuild the database from it on startup
+    #[arg(long, default_value_t = String::new())]
+    aof: String,
+    /// When to fsync the append-only file: always, everysec or no
+    #[arg(long, default_value = "everysec")]
+    appendfsync: FsyncPolicy,
+    /// How to compress snapshots: none, lz4 or zstd. Snapshots are loaded whichever way they were compressed.
+    #[arg(long, default_value = "none")]
+    compression: Compression,
+}
+
+// Parses a number of bytes with an optional unit, like Redis's config file does.
+fn parse_memory_size(s: &str) -> Result<usize, String> {
+    let s = s.trim().to_lowercase();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+
+    let number = number.parse::<usize>().map_err(|_| format!("invalid memory size: {}", s))?;
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" | "kb" => 1 << 10,
+        "m" | "mb" => 1 << 20,
+        "g" | "gb" => 1 << 30,
+        _ => return Err(format!("invalid memory unit: {}", unit)),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or(format!("memory size too large: {}", s))
 }
 
 struct Db {
-    table: Table,
+    // Keys are hashed with a random per-table seed so that clients can't flood a single bucket
+    table: Table<String, Value, SipBuildHasher>,
     path: Option<String>,
+    compression: Compression,
+    // The memory limit in bytes (0 for no limit)
+    maxmemory: usize,
+    eviction_policy: EvictionPolicy,
+    aof: Option<AppendOnlyFile>,
+    // The number of changes since the last save
+    changes: u64,
+    // When the database was last saved, as a Unix time in seconds
+    last_save: u64,
+    bgsave_in_progress: bool,
+    // The outcome of the last background save, and when it finished
+    last_bgsave_ok: bool,
+    last_bgsave_finished: u64,
+    // How long the last background save took, in seconds
+    last_bgsave_duration: Option<u64>,
+}
+
+// How often the server samples keys with an expiry time and removes the expired ones
+const EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+// The number of buckets sampled per round of active expiry
+const EXPIRE_SAMPLES: usize = 20;
+// Caps the rounds per interval so that a mass expiry can't hold the lock for too long
+const EXPIRE_MAX_ROUNDS: usize = 16;
+
+// Like Redis, keeps sampling as long as more than a quarter of the sampled keys turn out to be expired.
+async fn expire_keys(shared: Arc<Mutex<Db>>) {
+    let mut interval = tokio::time::interval(EXPIRE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let mut db = shared.lock().unwrap();
+        for _ in 0..EXPIRE_MAX_ROUNDS {
+            let (checked, removed) = db.table.expire_sample(EXPIRE_SAMPLES);
+            db.changes += removed as u64;
+            if removed * 4 <= checked {
+                break;
+            }
+        }
+    }
+}
+
+// How often the save schedule is checked
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(1);
+// How long to wait after a failed save before trying again, so that a full disk doesn't get hammered
+const AUTOSAVE_RETRY_DELAY: u64 = 5;
+
+async fn autosave(shared: Arc<Mutex<Db>>, schedule: SaveSchedule) {
+    let mut interval = tokio::time::interval(AUTOSAVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let mut db = shared.lock().unwrap();
+        let now = unix_time_ms() / 1000;
+        let retrying_too_soon =
+            !db.last_bgsave_ok && now < db.last_bgsave_finished + AUTOSAVE_RETRY_DELAY;
+        if db.bgsave_in_progress
+            || retrying_too_soon
+            || !schedule.is_due(now.saturating_sub(db.last_save), db.changes)
+        {
+            continue;
+        }
+
+        println!("{} changes since the last save, saving in the background", db.changes);
+        if let Err(e) = bg_save(&mut db, shared.clone()) {
+            println!("Error: failed to start a background save: {}", e);
+        }
+    }
+}
+
+fn snapshot_path(db: &Db) -> Result<String, Error> {
+    match db.path.as_deref() {
+        Some(path) if !path.is_empty() => Ok(path.to_string()),
+        _ => Err(Error::Unavailable("no database file was provided".to_string())),
+    }
+}
+
+// Writes the database to disk, and starts counting changes again if that worked
+fn save(db: &mut Db) -> Result<(), Error> {
+    if db.bgsave_in_progress {
+        return Err(Error::Unavailable("Background save already in progress".to_string()));
+    }
+    let path = snapshot_path(db)?;
+    db.table.to_disk_compressed(&path, db.compression)?;
+
+    db.changes = 0;
+    db.last_save = unix_time_ms() / 1000;
+    Ok(())
+}
+
+// Saves the database on a blocking thread. Only cloning the table happens under the lock, so clients
+// are held up for much less time than the serialization and disk write would take.
+fn bg_save(db: &mut Db, shared: Arc<Mutex<Db>>) -> Result<Value, Error> {
+    if db.bgsave_in_progress {
+        return Err(Error::Unavailable("Background save already in progress".to_string()));
+    }
+    let path = snapshot_path(db)?;
+
+    db.bgsave_in_progress = true;
+    let table = db.table.clone();
+    let compression = db.compression;
+    let changes = db.changes;
+    // Timed with the monotonic clock, since the wall clock can go backwards while the save runs
+    let started = Instant::now();
+    tokio::task::spawn_blocking(move || {
+        let result = table.to_disk_compressed(&path, compression);
+
+        let mut db = shared.lock().unwrap();
+        let now = unix_time_ms() / 1000;
+        db.bgsave_in_progress = false;
+        db.last_bgsave_ok = result.is_ok();
+        db.last_bgsave_finished = now;
+        db.last_bgsave_duration = Some(started.elapsed().as_secs());
+        match result {
+            Ok(()) => {
+                // Changes made while the snapshot was being written aren't in it
+                db.changes = db.changes.saturating_sub(changes);
+                db.last_save = now;
+                println!("Background save finished");
+            }
+            Err(e) => println!("Error: background save failed: {}", e),
+        }
+    });
+
+    Ok(Value::SimpleString("Background saving started".to_string()))
+}
+
+// The persistence section of INFO, in Redis's format
+fn info_persistence(db: &Db) -> String {
+    let fields = [
+        ("rdb_changes_since_last_save", db.changes.to_string()),
+        ("rdb_bgsave_in_progress", (db.bgsave_in_progress as u8).to_string()),
+        ("rdb_last_save_time", db.last_save.to_string()),
+        ("rdb_last_bgsave_status", if db.last_bgsave_ok { "ok" } else { "err" }.to_string()),
+        (
+            "rdb_last_bgsave_time_sec",
+            db.last_bgsave_duration.map_or("-1".to_string(), |d| d.to_string()),
+        ),
+        ("aof_enabled", (db.aof.is_some() as u8).to_string()),
+        (
+            "aof_rewrite_in_progress",
+            (db.aof.as_ref().is_some_and(|aof| aof.is_rewriting()) as u8).to_string(),
+        ),
+    ];
+
+    let mut info = "# Persistence\r\n".to_string();
+    for (name, value) in fields {
+        info.push_str(&format!("{}:{}\r\n", name, value));
+    }
+    info
 }
 
-fn dispatch(command: Command, shared: Arc<Mutex<Db>>) -> Result<Value, String> {
+async fn sync_aof(shared: Arc<Mutex<Db>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let mut db = shared.lock().unwrap();
+        if let Some(aof) = &mut db.aof {
+            if let Err(e) = aof.sync() {
+                println!("Error: failed to fsync the append-only file: {}", e);
+            }
+        }
+    }
+}
+
+// The time `ms` milliseconds after `now`, or None if that is in the past
+fn after(now: u64, ms: i64) -> Option<u64> {
+    if ms > 0 {
+        Some(now.saturating_add(ms as u64))
+    } else {
+        None
+    }
+}
+
+// Expires a key at the given time, deleting it right away if that is in the past.
+fn expire_at(db: &mut Db, key: &str, timestamp: Option<u64>, now: u64) -> Value {
+    let exists = match timestamp {
+        Some(timestamp) if timestamp > now => db.table.set_expiry(key, Some(timestamp)),
+        _ => db.table.remove(key).is_some(),
+    };
+    Value::Int(exists as i64)
+}
+
+// The remaining time to live of a key in milliseconds, or -1 if it has no expiry and -2 if it doesn't exist.
+fn ttl_ms(db: &mut Db, key: &str) -> i64 {
+    match db.table.get_entry(key).map(|entry| entry.expires_at()) {
+        Some(Some(expires_at)) => expires_at.saturating_sub(unix_time_ms()) as i64,
+        Some(None) => -1,
+        None => -2,
+    }
+}
+
+fn add_to_int(value: &mut Value, n: i64) -> Result<(), Error> {
+    match value {
+        Value::Int(i) => {
+            *i = i
+                .checked_add(n)
+                .ok_or(Error::InvalidArgument("increment or decrement would overflow".to_string()))?;
+            Ok(())
+        }
+        _ => Err(Error::WrongType),
+    }
+}
+
+// Commands that can make the table take up more memory, which are refused if the memory limit
+// has been reached and nothing can be evicted.
+fn uses_memory(command: &Command) -> bool {
+    matches!(command, Command::Set(..))
+}
+
+// Evicts entries until the table fits in the memory limit. Returns false if that isn't possible.
+fn free_memory(db: &mut Db) -> Result<bool, Error> {
+    while db.maxmemory > 0 && db.table.memory_usage() > db.maxmemory {
+        let Some(entry) = db.table.evict(db.eviction_policy) else {
+            return Ok(false);
+        };
+        db.changes += 1;
+
+        // Otherwise the evicted key would come back when the log is replayed
+        if let Some(aof) = &mut db.aof {
+            aof.append(&[Command::Del(entry.key().clone())])?;
+        }
+    }
+    Ok(true)
+}
+
+// The commands to write to the append-only file for a command that has been run at `now`.
+// Relative expiry times are logged as absolute ones, so that they don't restart on replay.
+fn aof_commands(command: &Command, now: u64) -> Vec<Command> {
+    let expire_at = |key: &String, ms: i64| match after(now, ms) {
+        Some(timestamp) => Command::PExpireAt(key.clone(), timestamp),
+        None => Command::Del(key.clone()),
+    };
+
+    match command {
+        Command::Set(key, value, Some(ms)) => vec![
+            Command::Set(key.clone(), value.clone(), None),
+            Command::PExpireAt(key.clone(), now.saturating_add(*ms)),
+        ],
+        Command::Expire(key, seconds) => vec![expire_at(key, seconds.saturating_mul(1000))],
+        Command::PExpire(key, ms) => vec![expire_at(key, *ms)],
+        Command::Set(..)
+        | Command::Inc(_)
+        | Command::Dec(_)
+        | Command::Del(_)
+        | Command::PExpireAt(..)
+        | Command::Persist(_) => vec![command.clone()],
+        _ => vec![],
+    }
+}
+
+// The commands that rebuild the current contents of the table, for rewriting the append-only file
+fn table_commands(table: &Table<String, Value, SipBuildHasher>) -> Vec<Command> {
+    let mut commands = Vec::with_capacity(table.len());
+    for entry in table.entries() {
+        commands.push(Command::Set(entry.key().clone(), entry.value().clone(), None));
+        if let Some(expires_at) = entry.expires_at() {
+            commands.push(Command::PExpireAt(entry.key().clone(), expires_at));
+        }
+    }
+    commands
+}
+
+// Rewrites the append-only file on a blocking thread, so that clients aren't held up while it is written.
+// Writes that happen in the meantime go to both the old file and a buffer that is added to the new one.
+fn bg_rewrite_aof(db: &mut Db, shared: Arc<Mutex<Db>>) -> Result<Value, Error> {
+    let Some(aof) = &mut db.aof else {
+        return Err(Error::Unavailable("append-only file is disabled".to_string()));
+    };
+    if !aof.start_rewrite() {
+        return Err(Error::Unavailable(
+            "Background append only file rewriting already in progress".to_string(),
+        ));
+    }
+
+    let path = aof.path().to_path_buf();
+    let commands = table_commands(&db.table);
+    tokio::task::spawn_blocking(move || {
+        let written = AppendOnlyFile::write_rewrite(&path, &commands);
+
+        let mut db = shared.lock().unwrap();
+        let Some(aof) = &mut db.aof else {
+            return;
+        };
+        match written.and_then(|_| aof.finish_rewrite()) {
+            Ok(()) => println!("Background append-only file rewrite finished"),
+            Err(e) => {
+                println!("Error: background append-only file rewrite failed: {}", e);
+                aof.abort_rewrite();
+            }
+        }
+    });
+
+    Ok(Value::SimpleString("Background append only file rewriting started".to_string()))
+}
+
+fn dispatch(command: Command, shared: Arc<Mutex<Db>>) -> Result<Value, Error> {
     let mut db = shared.lock().unwrap();
 
+    if !free_memory(&mut db)? && uses_memory(&command) {
+        return Err(Error::OutOfMemory);
+    }
+
     match command {
-        Command::Get(key) => Ok(db.table.get(&key).unwrap_or(Value::Null)),
-        Command::Set(key, value) => {
-            db.table.set(key, value);
+        Command::BgRewriteAof => return bg_rewrite_aof(&mut db, shared.clone()),
+        Command::BgSave => return bg_save(&mut db, shared.clone()),
+        _ => {}
+    }
+
+    // Log the command once it has been run successfully, using the same time that it was run at
+    let now = unix_time_ms();
+    let logged = aof_commands(&command, now);
+    let undo = match (&db.aof, logged.first().and_then(logged_key)) {
+        (Some(_), Some(key)) => Some((key.clone(), db.table.get_entry(key).cloned())),
+        _ => None,
+    };
+    let result = execute(&mut db, command, now)?;
+    if !logged.is_empty() {
+        if let Some(aof) = &mut db.aof {
+            if let Err(e) = aof.append(&logged) {
+                // Otherwise the write would be lost on the next restart, even though clients may have seen it
+                if let Some((key, entry)) = undo {
+                    restore(&mut db.table, key, entry);
+                }
+                return Err(e.into());
+            }
+        }
+        db.changes += 1;
+    }
+    Ok(result)
+}
+
+// The key written to by a command as it is logged. Every command in the log writes to exactly one.
+fn logged_key(command: &Command) -> Option<&String> {
+    match command {
+        Command::Set(key, ..)
+        | Command::Inc(key)
+        | Command::Dec(key)
+        | Command::Del(key)
+        | Command::PExpireAt(key, _)
+        | Command::Persist(key) => Some(key),
+        _ => None,
+    }
+}
+
+// Puts a key back the way it was before a write, given its entry from then (if it existed)
+fn restore(table: &mut Table<String, Value, SipBuildHasher>, key: String, entry: Option<Entry<String, Value>>) {
+    match entry {
+        Some(entry) => table.set_with_expiry(key, entry.value().clone(), entry.expires_at()),
+        None => {
+            table.remove(&key);
+        }
+    }
+}
+
+fn execute(db: &mut Db, command: Command, now: u64) -> Result<Value, Error> {
+    match command {
+        Command::Get(key) => Ok(db.table.get(&key).cloned().unwrap_or(Value::Null)),
+        Command::Set(key, value, expiry) => {
+            let expires_at = expiry.map(|ms| now.saturating_add(ms));
+            db.table.set_with_expiry(key, value, expires_at);
             Ok(Value::SimpleString("OK".to_string()))
         }
-        Command::Inc(key) => match db.table.get(&key) {
-            Some(Value::Int(i)) => {
-                db.table.set(key, Value::Int(i + 1));
-                Ok(Value::SimpleString("OK".to_string()))
-            }
-            _ => Ok(Value::SimpleError("cannot increment non-integer".to_string())),
+        // Modify integers in place so that they keep their expiry time
+        Command::Inc(key) => match db.table.update(&key, |value| add_to_int(value, 1)) {
+            Some(result) => result.map(|()| Value::SimpleString("OK".to_string())),
+            None => Err(Error::InvalidArgument("no such key".to_string())),
         },
-        Command::Dec(key) => match db.table.get(&key) {
-            Some(Value::Int(i)) => {
-                db.table.set(key, Value::Int(i - 1));
-                Ok(Value::SimpleString("OK".to_string()))
-            }
-            _ => Ok(Value::SimpleError("cannot decrement non-integer".to_string())),
+        Command::Dec(key) => match db.table.update(&key, |value| add_to_int(value, -1)) {
+            Some(result) => result.map(|()| Value::SimpleString("OK".to_string())),
+            None => Err(Error::InvalidArgument("no such key".to_string())),
+        },
+        Command::Del(key) => match db.table.remove(&key) {
+            Some(_) => Ok(Value::Int(1)),
+            None => Ok(Value::Int(0)),
         },
-        Command::Hello => {
-            Ok(Value::SimpleString("WORLD".to_string()))
+        Command::Scan(cursor, pattern, count) => {
+            let (cursor, entries) = db.table.scan(cursor, count.unwrap_or(10));
+            let keys = entries
+                .into_iter()
+                .map(|entry| entry.key())
+                .filter(|key| pattern.as_ref().is_none_or(|pattern| glob_match(pattern, key)))
+                .map(|key| Value::String(key.clone()))
+                .collect();
+            Ok(Value::Array(vec![
+                Value::String(cursor.to_string()),
+                Value::Array(keys),
+            ]))
+        }
+        Command::Expire(key, seconds) => {
+            Ok(expire_at(db, &key, after(now, seconds.saturating_mul(1000)), now))
         }
+        Command::PExpire(key, ms) => Ok(expire_at(db, &key, after(now, ms), now)),
+        Command::PExpireAt(key, timestamp) => Ok(expire_at(db, &key, Some(timestamp), now)),
+        Command::Ttl(key) => match ttl_ms(db, &key) {
+            // Round to the nearest second, like Redis
+            ms if ms >= 0 => Ok(Value::Int((ms + 500) / 1000)),
+            code => Ok(Value::Int(code)),
+        },
+        Command::PTtl(key) => Ok(Value::Int(ttl_ms(db, &key))),
+        Command::Persist(key) => {
+            let had_expiry = matches!(db.table.get_entry(&key), Some(entry) if entry.expires_at().is_some());
+            if had_expiry {
+                db.table.set_expiry(&key, None);
+            }
+            Ok(Value::Int(had_expiry as i64))
+        }
+        Command::Ping(None) => Ok(Value::SimpleString("PONG".to_string())),
+        Command::Ping(Some(message)) => Ok(Value::String(message)),
         Command::Save => {
-            if let Some(path) = &db.path {
-                if !path.is_empty() {
-                    db.table.to_disk(path.as_str()).unwrap();
+            save(db)?;
+            Ok(Value::SimpleString("OK".to_string()))
+        }
+        Command::LastSave => Ok(Value::Int(db.last_save as i64)),
+        Command::Info(section) => match section.as_deref().map(str::to_lowercase).as_deref() {
+            None | Some("persistence") | Some("all") | Some("everything") => {
+                Ok(Value::VerbatimString("txt".to_string(), info_persistence(db)))
+            }
+            // Like Redis, an unknown section is just empty
+            Some(_) => Ok(Value::VerbatimString("txt".to_string(), String::new())),
+        },
+        // These need more than the database, so `dispatch` and `handle` deal with them before getting here
+        Command::BgSave | Command::BgRewriteAof | Command::Hello(_) => {
+            Err(Error::Unavailable("command can't be run here".to_string()))
+        }
+        // Handled by the client itself, so the server never decodes them
+        Command::Help => Err(Error::UnknownCommand("HELP".to_string())),
+        Command::Exit => Err(Error::UnknownCommand("EXIT".to_string())),
+    }
+}
+
+// The reply to send for the result of a command. I/O errors are something going wrong inside the
+// server rather than with the request, so they are logged as well.
+fn reply(result: Result<Value, Error>) -> Value {
+    result.unwrap_or_else(|e| {
+        if let Error::Io(_) = e {
+            println!("Error: {}", e);
+        }
+        e.into()
+    })
+}
+
+// Runs a command from a client connection. HELLO is handled here rather than in `dispatch`, since
+// the protocol version belongs to the connection rather than the database.
+fn handle(command: Command, protocol: &mut Protocol, shared: Arc<Mutex<Db>>) -> Value {
+    match command {
+        Command::Hello(version) => reply(hello(protocol, version)),
+        command => reply(dispatch(command, shared)),
+    }
+}
+
+fn hello(protocol: &mut Protocol, version: Option<u64>) -> Result<Value, Error> {
+    if let Some(version) = version {
+        *protocol = Protocol::from_version(version).ok_or(Error::NoProto)?;
+    }
+
+    let field = |name: &str, value| (Value::String(name.to_string()), value);
+    Ok(Value::Map(vec![
+        field("server", Value::String("rustdb".to_string())),
+        field("version", Value::String(env!("CARGO_PKG_VERSION").to_string())),
+        field("proto", Value::Int(protocol.version() as i64)),
+        field("mode", Value::String("standalone".to_string())),
+        field("role", Value::String("master".to_string())),
+        field("modules", Value::Array(vec![])),
+    ]))
+}
+
+async fn serve_resp(listener: TcpListener, shared: Arc<Mutex<Db>>) {
+    while let Ok((stream, address)) = listener.accept().await {
+        println!("Accepting RESP connection from {}", address);
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_resp_connection(stream, shared).await {
+                println!("Error: {}", e);
+            }
+            println!("Connection from {} closed", address);
+        });
+    }
+}
+
+// Answers every complete request in the buffer, leaving any partial one for when more input arrives.
+// Returns the encoded replies, in order, and whether the connection should stay open: after a
+// protocol error, there's no telling where the next request starts, so the connection is closed.
+fn answer_requests(codec: &mut RespCodec, buffer: &mut BytesMut, shared: &Arc<Mutex<Db>>) -> (BytesMut, bool) {
+    let mut replies = BytesMut::new();
+    loop {
+        let reply = match codec.decode(buffer) {
+            Ok(Some(request)) => {
+                // Empty requests (e.g. blank lines) are ignored
+                if request == Value::Array(vec![]) {
+                    continue;
+                }
+                match Command::from_value(request) {
+                    Ok(command) => handle(command, &mut codec.protocol, shared.clone()),
+                    Err(e) => e.into(),
                 }
             }
-            Ok(Value::SimpleString("OK".to_string()))
+            Ok(None) => return (replies, true),
+            Err(e) => {
+                replies.extend_from_slice(&Value::from(e).encode(codec.protocol));
+                return (replies, false);
+            }
+        };
+        replies.extend_from_slice(&reply.encode(codec.protocol));
+    }
+}
+
+// Reads requests straight off the socket. Several requests can arrive at once (when a client pipelines
+// them), in which case they are all answered together.
+async fn handle_resp_connection(mut stream: TcpStream, shared: Arc<Mutex<Db>>) -> std::io::Result<()> {
+    let mut buffer = BytesMut::new();
+    let mut codec = RespCodec::new();
+    codec.inline = true;
+    loop {
+        let (replies, keep_open) = answer_requests(&mut codec, &mut buffer, &shared);
+        stream.write_all(&replies).await?;
+        if !keep_open {
+            return Ok(());
+        }
+
+        let len = buffer.len();
+        if stream.read_buf(&mut buffer).await? == 0 {
+            return Ok(());
         }
-        _ => Ok(Value::SimpleString("OK".to_string()))
+        println!("Received message: \"{}\"", escape_bytes(&buffer[len..]));
     }
 }
 
+// The handshake happens here rather than in the accept loop, so that a client that botches it only
+// loses its own connection.
+async fn handle_websocket_connection(stream: TcpStream, shared: Arc<Mutex<Db>>) -> Result<(), tokio_websockets::Error> {
+    let (_request, mut ws_stream) = ServerBuilder::new().accept(stream).await?;
+
+    // A message can hold several requests, and a request can be split across messages, so
+    // messages are treated as one stream of input like on a plain connection. The replies to
+    // the requests completed by each message are sent back together in one message.
+    let mut buffer = BytesMut::new();
+    let mut codec = RespCodec::new();
+    while let Some(Ok(msg)) = ws_stream.next().await {
+        if msg.is_text() || msg.is_binary() {
+            let msg = msg.as_payload();
+            println!("Received message: \"{}\"", escape_bytes(msg));
+            buffer.extend_from_slice(msg);
+
+            let (replies, keep_open) = answer_requests(&mut codec, &mut buffer, &shared);
+            if !replies.is_empty() {
+                ws_stream.send(websocket_message(replies.to_vec())).await?;
+            }
+            if !keep_open {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
 #[tokio::main]
-pub async fn main() -> Result<(), Error> {
+pub async fn main() -> Result<(), tokio_websockets::Error> {
     let cli = Cli::parse();
 
-    let table = if cli.path.is_empty() {
+    // An empty append-only file is one that was never written to, e.g. from a crash right after it was created
+    let aof_has_data = fs::metadata(&cli.aof).is_ok_and(|metadata| metadata.len() > 0);
+    let mut table = if !cli.aof.is_empty() && aof_has_data {
+        // The append-only file has everything, so there's no need to look at the snapshot
+        println!("Loading database from append-only file {}", cli.aof);
+        Table::with_hasher(SipBuildHasher::random())
+    } else if cli.path.is_empty() {
         println!("No database file provided, running in-memory mode");
-        Table::new()
+        Table::with_hasher(SipBuildHasher::random())
     } else if Path::new(&cli.path).exists() {
         println!("Loading database from {}", cli.path);
-        Table::from_disk(&cli.path).unwrap()
+        let (header, table) =
+            Table::load_keyspace(Path::new(&cli.path), SipBuildHasher::random()).unwrap_or_else(|e| {
+                eprintln!("Error: failed to load {}: {}", cli.path, e);
+                std::process::exit(1);
+            });
+        if header.is_none() {
+            // Rewrite snapshots from before the header was added straight away, rather than on the next save
+            println!("Note: {} has no header, converting it to format version {}", cli.path, FORMAT_VERSION);
+            write_snapshot(Path::new(&cli.path), &table, cli.compression).unwrap_or_else(|e| {
+                eprintln!("Error: failed to convert {}: {}", cli.path, e);
+                std::process::exit(1);
+            });
+        }
+        table
     } else {
         println!("Database file not found, creating new database");
-        Table::new()
+        Table::with_hasher(SipBuildHasher::random())
     };
+    // The memory limit is checked against this
+    table.track_memory();
 
+    let autosave_enabled = cli.autosave && !cli.no_autosave && !cli.save.is_empty();
     if !cli.path.is_empty() {
-        if cli.autosave {
-            println!("Note: autosave is enabled, database will be saved to disk every hour.");
+        if autosave_enabled {
+            println!("Note: autosave is enabled, using the save points \"{}\".", cli.save);
         } else {
             println!("Note: autosave is disabled, database must be saved manually using the SAVE command.");
         }
-    } else if cli.autosave {
+    } else if autosave_enabled {
         println!("Note: autosave is enabled, but no database file was provided. Data will be lost on exit.");
     }
 
-    let db = Db {
+    if cli.maxmemory > 0 {
+        println!(
+            "Note: memory is limited to {} bytes, using the {:?} eviction policy.",
+            cli.maxmemory, cli.maxmemory_policy
+        );
+    }
+
+    let has_path = !cli.path.is_empty();
+    let mut db = Db {
         table,
         path: Some(cli.path),
+        compression: cli.compression,
+        maxmemory: cli.maxmemory,
+        eviction_policy: cli.maxmemory_policy,
+        aof: None,
+        changes: 0,
+        last_save: unix_time_ms() / 1000,
+        bgsave_in_progress: false,
+        last_bgsave_ok: true,
+        last_bgsave_finished: 0,
+        last_bgsave_duration: None,
     };
 
+    if !cli.aof.is_empty() {
+        let path = Path::new(&cli.aof);
+        let mut loaded_len = None;
+        if path.exists() {
+            let (commands, len) = AppendOnlyFile::load(path).unwrap_or_else(|e| {
+                eprintln!("Error: failed to load {}: {}", cli.aof, e);
+                std::process::exit(1);
+            });
+            println!("Replaying {} commands", commands.len());
+            for command in commands {
+                if let Err(e) = execute(&mut db, command, unix_time_ms()) {
+                    println!("Error: {}", e);
+                }
+            }
+            loaded_len = Some(len);
+        }
+
+        let mut aof = AppendOnlyFile::open(path, cli.appendfsync)?;
+        // Like Redis's aof-load-truncated, drop a truncated command at the end before anything is added after it
+        if let Some(len) = loaded_len {
+            aof.truncate(len)?;
+        }
+        if aof.is_empty()? {
+            // Start the log off with whatever was loaded from the snapshot
+            aof.start_rewrite();
+            AppendOnlyFile::write_rewrite(path, &table_commands(&db.table))?;
+            aof.finish_rewrite()?;
+        }
+        println!("Note: logging writes to {}, fsync policy is {:?}.", cli.aof, cli.appendfsync);
+        db.aof = Some(aof);
+    }
+
     let shared = Arc::new(Mutex::new(db));
 
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", cli.port)).await?;
+    tokio::spawn(expire_keys(shared.clone()));
+    if autosave_enabled && has_path {
+        tokio::spawn(autosave(shared.clone(), cli.save));
+    }
+    if cli.appendfsync == FsyncPolicy::EverySec {
+        tokio::spawn(sync_aof(shared.clone()));
+    }
 
-    while let Ok((stream, _)) = listener.accept().await {
-        let (_request, mut ws_stream) = ServerBuilder::new().accept(stream).await?;
-        let shared = shared.clone();
+    if let Some(port) = cli.resp_port {
+        let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).await?;
+        println!("Note: listening for RESP connections on port {}.", port);
+        tokio::spawn(serve_resp(listener, shared.clone()));
+    }
 
-        println!("Accepting connection from {}", ws_stream.get_ref().peer_addr().unwrap());
+    let listener = TcpListener::bind(format!("127.0.0.1:{}", cli.port)).await?;
 
+    while let Ok((stream, address)) = listener.accept().await {
+        println!("Accepting connection from {}", address);
+        let shared = shared.clone();
         tokio::spawn(async move {
-            while let Some(Ok(msg)) = ws_stream.next().await {
-                if msg.is_text() || msg.is_binary() {
-                    let msg = msg.as_text().unwrap().to_string();
-                    println!("Received message: {:?}", msg);
-                    let command = Command::decode_resp(msg).unwrap();
-                    let result = dispatch(command, shared.clone());
-                    match result {
-                        Ok(value) => ws_stream.send(Message::text(value.encode_resp())).await?,
-                        Err(e) => {
-                            println!("Error: {}", e);
-                            ws_stream.send(Message::text(Value::SimpleError("SERVER ERROR".to_string()).encode_resp())).await?;
-                        }
-                    }
-                }
+            if let Err(e) = handle_websocket_connection(stream, shared).await {
+                println!("Error: {}", e);
             }
-
-            println!("Connection from {} closed", ws_stream.get_ref().peer_addr().unwrap());
-            Ok::<_, Error>(())
+            println!("Connection from {} closed", address);
         });
     }
 
@@ -0,0 +1,460 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::table::unix_time_ms;
+
+// A rule for when to save a snapshot: after `seconds` have passed since the last save, as long as
+// there have been at least `changes` writes in the meantime. Modelled on Redis's `save` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavePoint {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+// A list of save points, any one of which triggers a save, written as pairs of numbers like
+// "3600 1 300 100". An empty string means no automatic saves.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SaveSchedule(pub Vec<SavePoint>);
+
+impl SaveSchedule {
+    // Whether a save is due, given how long it has been since the last one and how many changes
+    // have been made since
+    pub fn is_due(&self, seconds: u64, changes: u64) -> bool {
+        changes > 0
+            && self
+                .0
+                .iter()
+                .any(|point| seconds >= point.seconds && changes >= point.changes)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromStr for SaveSchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let numbers = s
+            .split_whitespace()
+            .map(|n| n.parse::<u64>().map_err(|_| format!("invalid save point: {}", n)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if numbers.len() % 2 != 0 {
+            return Err("save points must be pairs of <seconds> <changes>".to_string());
+        }
+
+        let points = numbers
+            .chunks(2)
+            .map(|pair| SavePoint {
+                seconds: pair[0],
+                changes: pair[1],
+            })
+            .collect();
+        Ok(Self(points))
+    }
+}
+
+impl fmt::Display for SaveSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pairs = self
+            .0
+            .iter()
+            .map(|point| format!("{} {}", point.seconds, point.changes))
+            .collect::<Vec<_>>();
+        write!(f, "{}", pairs.join(" "))
+    }
+}
+
+// Numbers the temporary files written by this process, so that two saves at once don't share one
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// A new file for a snapshot to be written to before it replaces the one at `path`. Its name includes the
+// process ID and a counter, so that concurrent writers (e.g. rustdb-tool while the server is saving)
+// never rename each other's half-written files into place.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(
+        ".tmp-{}-{}",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    PathBuf::from(name)
+}
+
+// Replaces the file at `path` without ever leaving it half-written: the new contents go to a temporary
+// file, which is fsynced and then renamed over the old one. If anything goes wrong (including a crash)
+// before the rename, the old file is left as it was.
+pub fn write_atomically<E, F>(path: &Path, write: F) -> Result<(), E>
+where
+    E: From<io::Error>,
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), E>,
+{
+    let temp = temp_path(path);
+    let result = (|| {
+        let mut writer = BufWriter::new(File::create_new(&temp)?);
+        write(&mut writer)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&temp, path)?;
+        sync_parent_dir(path)?;
+        Ok(())
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+// Makes a rename in the file's directory durable. Without this, the rename itself could be lost
+// in a crash, even though the file's contents were synced.
+pub fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+// Snapshot files start with a fixed-size header, followed by the bincode-encoded table:
+//
+//   magic        6 bytes   "RUSTDB"
+//   version      u16       FORMAT_VERSION
+//   compression  u8        see `Compression` (not present in version 1, which is never compressed)
+//   created_at   u64       Unix time in milliseconds
+//   payload_len  u64       length of the payload in bytes, as stored
+//   checksum     u32       CRC-32 of the payload, as stored
+//
+// All numbers are little-endian. Files written before the header was introduced are just the
+// payload on its own, and are still read (see `read_snapshot`).
+pub const MAGIC: &[u8; 6] = b"RUSTDB";
+pub const FORMAT_VERSION: u16 = 2;
+pub const HEADER_LEN: usize = 29;
+
+// How the payload of a snapshot is compressed. Stored in the header as a single byte.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    // Fast, with a modest reduction in size
+    Lz4,
+    // Slower, but much smaller
+    Zstd,
+}
+
+// The zstd level used when saving. Higher levels are much slower for little gain on this kind of data.
+const ZSTD_LEVEL: i32 = 3;
+
+// lz4 can't shrink data by more than this, since a byte of a match length stands for at most 255 bytes
+const LZ4_MAX_RATIO: usize = 255;
+
+impl Compression {
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self, SnapshotError> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            _ => Err(SnapshotError::UnknownCompression(id)),
+        }
+    }
+
+    pub fn compress(self, bytes: Vec<u8>) -> Result<Vec<u8>, SnapshotError> {
+        match self {
+            Compression::None => Ok(bytes),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(&bytes)),
+            Compression::Zstd => Ok(zstd::bulk::compress(&bytes, ZSTD_LEVEL)?),
+        }
+    }
+
+    pub fn decompress(self, bytes: &[u8]) -> Result<Vec<u8>, SnapshotError> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Lz4 => {
+                // The size prefix is checked before anything is allocated for it, so that a damaged
+                // one can't make it ask for gigabytes
+                let (size, block) = lz4_flex::block::uncompressed_size(bytes)
+                    .map_err(|e| SnapshotError::Decompression(e.to_string()))?;
+                if size > block.len().saturating_mul(LZ4_MAX_RATIO) {
+                    return Err(SnapshotError::Decompression(format!(
+                        "size prefix of {} bytes is more than {} bytes of lz4 data can hold",
+                        size,
+                        block.len()
+                    )));
+                }
+                lz4_flex::block::decompress(block, size).map_err(|e| SnapshotError::Decompression(e.to_string()))
+            }
+            Compression::Zstd => {
+                zstd::decode_all(bytes).map_err(|e| SnapshotError::Decompression(e.to_string()))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("unknown compression: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u16,
+    pub compression: Compression,
+    pub created_at: u64,
+    pub payload_len: u64,
+    pub checksum: u32,
+}
+
+impl Header {
+    // The size of the header in a file of the given version
+    pub fn encoded_len(version: u16) -> usize {
+        match version {
+            1 => 28,
+            _ => HEADER_LEN,
+        }
+    }
+
+    // Always encodes the current version
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..6].copy_from_slice(MAGIC);
+        bytes[6..8].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes[8] = self.compression.id();
+        bytes[9..17].copy_from_slice(&self.created_at.to_le_bytes());
+        bytes[17..25].copy_from_slice(&self.payload_len.to_le_bytes());
+        bytes[25..29].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    // Recognises a header whose magic has been damaged, by the version (and compression) that follow it.
+    // A headerless snapshot can't be mistaken for one, since it starts with a bucket count whose high bytes are 0.
+    pub fn decode_damaged(bytes: &[u8]) -> Option<Header> {
+        let mut patched = bytes.get(..HEADER_LEN.min(bytes.len()))?.to_vec();
+        patched.get_mut(..MAGIC.len())?.copy_from_slice(MAGIC);
+        Header::decode(&patched).ok().flatten()
+    }
+
+    // Reads the header at the start of a file. Returns None if there isn't one (i.e. the magic is missing).
+    pub fn decode(bytes: &[u8]) -> Result<Option<Header>, SnapshotError> {
+        if !bytes.starts_with(MAGIC) {
+            return Ok(None);
+        }
+        // Check the version first, since a later version might not have the same header layout
+        let Some(version) = bytes.get(6..8) else {
+            return Err(SnapshotError::Truncated {
+                expected: HEADER_LEN as u64,
+                actual: bytes.len() as u64,
+            });
+        };
+        let version = u16::from_le_bytes(version.try_into().unwrap());
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let len = Header::encoded_len(version);
+        if bytes.len() < len {
+            return Err(SnapshotError::Truncated {
+                expected: len as u64,
+                actual: bytes.len() as u64,
+            });
+        }
+
+        // Version 1 is the same apart from not having the compression byte
+        let (compression, rest) = match version {
+            1 => (Compression::None, &bytes[8..len]),
+            _ => (Compression::from_id(bytes[8])?, &bytes[9..len]),
+        };
+        Ok(Some(Header {
+            version,
+            compression,
+            created_at: u64::from_le_bytes(rest[0..8].try_into().unwrap()),
+            payload_len: u64::from_le_bytes(rest[8..16].try_into().unwrap()),
+            checksum: u32::from_le_bytes(rest[16..20].try_into().unwrap()),
+        }))
+    }
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    // The file has no header, and isn't a valid headerless snapshot either
+    NotASnapshot(bincode::Error),
+    UnsupportedVersion(u16),
+    UnknownCompression(u8),
+    // The file is shorter than the header says it should be
+    Truncated { expected: u64, actual: u64 },
+    // The file is longer than the header says it should be
+    TrailingData { expected: u64, actual: u64 },
+    ChecksumMismatch { expected: u32, actual: u32 },
+    Decompression(String),
+    // The payload passed the checksum but couldn't be decoded (or encoded, when saving)
+    Serialization(bincode::Error),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::NotASnapshot(e) => write!(f, "not a snapshot file ({})", e),
+            SnapshotError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported snapshot format version {} (this build supports up to version {})",
+                version, FORMAT_VERSION
+            ),
+            SnapshotError::UnknownCompression(id) => write!(f, "unknown snapshot compression {}", id),
+            SnapshotError::Truncated { expected, actual } => write!(
+                f,
+                "snapshot is truncated: expected {} bytes, found {}",
+                expected, actual
+            ),
+            SnapshotError::TrailingData { expected, actual } => write!(
+                f,
+                "snapshot has trailing data: expected {} bytes, found {}",
+                expected, actual
+            ),
+            SnapshotError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "snapshot checksum mismatch: expected {:08x}, found {:08x}",
+                expected, actual
+            ),
+            SnapshotError::Decompression(e) => write!(f, "failed to decompress snapshot: {}", e),
+            SnapshotError::Serialization(e) => write!(f, "invalid snapshot payload: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+// Atomically writes a snapshot of `value` to `path`, with a header.
+pub fn write_snapshot<T: Serialize>(
+    path: &Path,
+    value: &T,
+    compression: Compression,
+) -> Result<(), SnapshotError> {
+    let payload = bincode::serialize(value).map_err(SnapshotError::Serialization)?;
+    let payload = compression.compress(payload)?;
+    let header = Header {
+        version: FORMAT_VERSION,
+        compression,
+        created_at: unix_time_ms(),
+        payload_len: payload.len() as u64,
+        checksum: crc32fast::hash(&payload),
+    };
+
+    write_atomically(path, |writer| {
+        writer.write_all(&header.encode())?;
+        writer.write_all(&payload)?;
+        Ok(())
+    })
+}
+
+// Reads a snapshot, checking its header and checksum. Returns the header along with the value,
+// or None in place of the header for a file in the old headerless format.
+pub fn read_snapshot<T: DeserializeOwned>(path: &Path) -> Result<(Option<Header>, T), SnapshotError> {
+    let (header, payload) = read_payload(path)?;
+    let value = bincode::deserialize(&payload).map_err(|e| match header {
+        Some(_) => SnapshotError::Serialization(e),
+        None => SnapshotError::NotASnapshot(e),
+    })?;
+    Ok((header, value))
+}
+
+// Reads the (decompressed) payload of a snapshot, checking its header and checksum
+pub fn read_payload(path: &Path) -> Result<(Option<Header>, Vec<u8>), SnapshotError> {
+    let bytes = fs::read(path)?;
+
+    let Some(header) = Header::decode(&bytes)? else {
+        return Ok((None, bytes));
+    };
+
+    let payload = &bytes[Header::encoded_len(header.version)..];
+    let actual = payload.len() as u64;
+    if actual < header.payload_len {
+        return Err(SnapshotError::Truncated {
+            expected: header.payload_len,
+            actual,
+        });
+    }
+    if actual > header.payload_len {
+        return Err(SnapshotError::TrailingData {
+            expected: header.payload_len,
+            actual,
+        });
+    }
+
+    let checksum = crc32fast::hash(payload);
+    if checksum != header.checksum {
+        return Err(SnapshotError::ChecksumMismatch {
+            expected: header.checksum,
+            actual: checksum,
+        });
+    }
+
+    Ok((Some(header), header.compression.decompress(payload)?))
+}
+
+// Gets as much of the payload out of a damaged snapshot as possible, ignoring its length and checksum.
+// A zstd payload is decompressed up to the damage, but an lz4 one has to be intact to be decompressed at all.
+pub fn salvage_payload(path: &Path) -> Result<(Option<Header>, Vec<u8>), SnapshotError> {
+    let bytes = fs::read(path)?;
+
+    let Some(header) = Header::decode(&bytes)?.or_else(|| Header::decode_damaged(&bytes)) else {
+        return Ok((None, bytes));
+    };
+
+    let payload = &bytes[Header::encoded_len(header.version)..];
+    let payload = &payload[..payload.len().min(header.payload_len as usize)];
+    let payload = match header.compression {
+        Compression::Zstd => {
+            let mut decompressed = Vec::new();
+            // Whatever was decompressed before an error is kept
+            let _ = zstd::stream::read::Decoder::new(payload)?.read_to_end(&mut decompressed);
+            decompressed
+        }
+        compression => compression.decompress(payload)?,
+    };
+    Ok((Some(header), payload))
+}
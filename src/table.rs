@@ -1,114 +1,642 @@
+use bincode::Options;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
 use std::fmt;
-use std::fs::File;
+use std::hash::{BuildHasher, Hash};
+use std::mem::size_of;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::hash::FnvBuildHasher;
+use crate::memory::MemoryUsage;
+use crate::snapshot::{read_payload, read_snapshot, write_snapshot, Compression, Header, SnapshotError};
 use crate::value::Value;
 
-#[derive(Clone, PartialEq, Serialize, Deserialize)]
-struct Entry {
-    key: String,
-    value: Value,
+// Milliseconds since the Unix epoch. Expiry times are stored as absolute timestamps so that
+// they still mean the same thing after a snapshot is loaded by a later process.
+pub fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the Unix epoch")
+        .as_millis() as u64
 }
 
-impl fmt::Debug for Entry {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "({} -> {:?})", self.key, self.value)
+// The initial LFU counter of new entries, so that they aren't the first to be evicted
+const LFU_INIT: u8 = 5;
+// The higher this is, the more accesses it takes to increment an LFU counter that is already high
+const LFU_LOG_FACTOR: f64 = 10.0;
+// LFU counters are decremented once per this many accesses to the rest of the table
+const LFU_DECAY_PERIOD: u64 = 10_000;
+
+// Recency and frequency information used to pick entries for eviction.
+// Like Redis, frequencies are tracked with a logarithmic counter that decays over time.
+#[derive(Debug, Clone, Copy)]
+struct Access {
+    // The value of the table's access clock when the entry was last used
+    last_used: u64,
+    frequency: u8,
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Self {
+            last_used: 0,
+            frequency: LFU_INIT,
+        }
+    }
+}
+
+impl Access {
+    fn decayed_frequency(&self, clock: u64) -> u8 {
+        let periods = (clock.saturating_sub(self.last_used) / LFU_DECAY_PERIOD).min(u8::MAX as u64);
+        self.frequency.saturating_sub(periods as u8)
+    }
+
+    fn touch(&mut self, clock: u64) {
+        let frequency = self.decayed_frequency(clock);
+        let p = 1.0 / ((frequency.saturating_sub(LFU_INIT)) as f64 * LFU_LOG_FACTOR + 1.0);
+        self.frequency = if frequency < u8::MAX && rand::random::<f64>() < p {
+            frequency + 1
+        } else {
+            frequency
+        };
+        self.last_used = clock;
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Entry<K, V> {
+    key: K,
+    value: V,
+
+    // When the entry expires (in Unix milliseconds), if ever
+    expires_at: Option<u64>,
+
+    // Only meaningful while the table is in memory, so it isn't saved with snapshots
+    #[serde(skip)]
+    access: Access,
+}
+
+// Entries are equal if they hold the same data, no matter how they have been accessed
+impl<K: PartialEq, V: PartialEq> PartialEq for Entry<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key && self.value == other.value && self.expires_at == other.expires_at
+    }
+}
+
+impl<K, V> Entry<K, V> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn value(&self) -> &V {
+        &self.value
+    }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
     }
 }
 
-fn hash(key: &str, level: usize) -> usize {
-    // Use FNV-1a hash algorithm
-    let mut hash: u64 = 14695981039346656037; // FNV offset basis
-    for byte in key.bytes() {
-        hash = hash ^ (byte as u64);
-        hash = hash.wrapping_mul(1099511628211); // FNV prime
+impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for Entry<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({:?} -> {:?})", self.key, self.value)
     }
+}
 
-    // Use level to determine number of bits to use from hash
+// Use level to determine number of bits to use from hash
+fn bucket_at_level(hash: u64, level: usize) -> usize {
     hash as usize % (1 << level)
 }
 
-const OVERFLOW_SIZE: usize = 1;
+// Increments the masked bits of a scan cursor, starting from the most significant one.
+// Counting in reverse-binary order means that when the table grows, the hash classes that have
+// already been visited split into classes whose cursors are all smaller than the current one,
+// so a scan that is interrupted by splits never skips a key (see Redis's dictScan).
+fn next_cursor(cursor: u64, mask: u64) -> u64 {
+    let cursor = cursor | !mask;
+    cursor.reverse_bits().wrapping_add(1).reverse_bits()
+}
+
+// The approximate memory taken up by an entry, not counting the bucket it is stored in
+fn entry_size<K: MemoryUsage, V: MemoryUsage>(key: &K, value: &V) -> usize {
+    size_of::<Entry<K, V>>() + key.heap_size() + value.heap_size()
+}
+
+// The number of buckets holding a candidate that are looked at when picking an entry to evict (see `Table::evict`)
+const EVICTION_SAMPLES: usize = 5;
+
+// Which entries to evict when the server goes over its memory limit. Modelled on Redis's maxmemory-policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    // Never evict anything, writes fail instead
+    NoEviction,
+    // Evict the least recently used entries
+    AllKeysLru,
+    // Evict the least frequently used entries
+    AllKeysLfu,
+    // Evict the entries closest to expiring, out of the ones that have an expiry time
+    VolatileTtl,
+    // Evict entries at random
+    AllKeysRandom,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "noeviction" => Ok(Self::NoEviction),
+            "allkeys-lru" => Ok(Self::AllKeysLru),
+            "allkeys-lfu" => Ok(Self::AllKeysLfu),
+            "volatile-ttl" => Ok(Self::VolatileTtl),
+            "allkeys-random" => Ok(Self::AllKeysRandom),
+            _ => Err(format!("unknown eviction policy: {}", s)),
+        }
+    }
+}
+
+// The last bucket is merged back once the load factor drops below this fraction of the target.
+// Kept well below 1 so that alternating inserts and removes don't thrash between splitting and merging.
+const MERGE_THRESHOLD: f64 = 0.5;
+
+// Tuning knobs for controlled splitting.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedConfig")]
+pub struct TableConfig {
+    // The target number of entries per bucket. A split happens whenever an insert pushes the
+    // table above it, and a merge whenever a removal takes it far enough below. Has to be positive.
+    pub load_factor: f64,
+
+    // The number of entries each bucket reserves space for up front (its "primary page").
+    // Entries past this spill over into reallocated storage. This only reserves space: when
+    // buckets split is decided by `load_factor` alone.
+    pub bucket_capacity: usize,
+}
+
+// A config as read from a snapshot, before it has been checked
+#[derive(Deserialize)]
+struct UncheckedConfig {
+    load_factor: f64,
+    bucket_capacity: usize,
+}
+
+// A damaged or hand-edited snapshot could otherwise hold a load factor that makes `set` split forever
+impl TryFrom<UncheckedConfig> for TableConfig {
+    type Error = String;
+
+    fn try_from(config: UncheckedConfig) -> Result<Self, Self::Error> {
+        if config.load_factor.is_nan() || config.load_factor <= 0.0 {
+            return Err(format!("load factor must be positive, not {}", config.load_factor));
+        }
+        Ok(Self {
+            load_factor: config.load_factor,
+            bucket_capacity: config.bucket_capacity,
+        })
+    }
+}
+
+impl Default for TableConfig {
+    fn default() -> Self {
+        Self {
+            load_factor: 4.0,
+            bucket_capacity: 4,
+        }
+    }
+}
 
 // TODO: experience with fixed-size arrays (page + overflow) or other data structures
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-struct Bucket {
+struct Bucket<K, V> {
     // We use a resizeable vector for chaining for simplicity's sake (and to avoid the horrors of using linked lists in Rust).
-    entries: Vec<Entry>,
+    entries: Vec<Entry<K, V>>,
 }
 
-impl Bucket {
-    fn new() -> Self {
+impl<K, V> Bucket<K, V> {
+    fn new(capacity: usize) -> Self {
         Self {
-            entries: Vec::new(),
+            entries: Vec::with_capacity(capacity),
         }
     }
 }
 
-// An in-memory hash table. Uses linear hashing with uncontrolled splitting.
-// Owns all of its contents and can be serialized to disk.
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub struct Table {
+// An in-memory hash table. Uses linear hashing with controlled splitting, i.e. buckets are split
+// based on the load factor of the whole table rather than whenever a single bucket overflows.
+// Owns all of its contents and can be serialized to disk (if its keys, values and hasher can be).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+// Otherwise serde would require K and V to implement Default, because of the skipped `measure`
+#[serde(bound(deserialize = "K: Deserialize<'de>, V: Deserialize<'de>, S: Deserialize<'de>"))]
+pub struct Table<K, V, S = FnvBuildHasher> {
     // The table consists of a vector of buckets, each containing multiple entries for chaining.
     // We also assume that the table has at least 2^current_level buckets.
-    data: Vec<Bucket>,
+    data: Vec<Bucket<K, V>>,
 
     // The current level of linear hashing
     current_level: usize,
 
     // The next bucket to split (linear hashing)
     next: usize,
+
+    // The total number of entries across all buckets
+    len: usize,
+
+    // The approximate memory taken up by all entries (see `entry_size`), if it is being tracked.
+    // Worked out from the entries, so it isn't saved with snapshots.
+    #[serde(skip)]
+    memory: usize,
+
+    // Works out the memory taken up by an entry, once `track_memory` has been called. A function can't
+    // be saved, so a table loaded from a snapshot has to start tracking again.
+    #[serde(skip)]
+    measure: Option<fn(&K, &V) -> usize>,
+
+    // Counts accesses to the table, used to order entries by recency for eviction
+    #[serde(skip)]
+    clock: u64,
+
+    config: TableConfig,
+
+    // Builds the hasher used to pick a bucket for each key
+    hasher: S,
 }
 
-// TODO: try to implement LH* (distributed linear hashing)
-impl Table {
+// Tables are equal if they hold the same data in the same layout, no matter how they have been accessed
+// or whether they are tracking their memory usage
+impl<K: PartialEq, V: PartialEq, S: PartialEq> PartialEq for Table<K, V, S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.data == other.data
+            && self.current_level == other.current_level
+            && self.next == other.next
+            && self.len == other.len
+            && self.config == other.config
+            && self.hasher == other.hasher
+    }
+}
+
+impl<K, V, S: Default> Default for Table<K, V, S> {
+    fn default() -> Self {
+        Self::with_config_and_hasher(TableConfig::default(), S::default())
+    }
+}
+
+impl<K, V> Table<K, V> {
     pub fn new() -> Self {
+        Self::with_config(TableConfig::default())
+    }
+
+    pub fn with_config(config: TableConfig) -> Self {
+        Self::with_config_and_hasher(config, FnvBuildHasher)
+    }
+}
+
+// TODO: try to implement LH* (distributed linear hashing)
+impl<K, V, S> Table<K, V, S> {
+    pub fn with_hasher(hasher: S) -> Self {
+        Self::with_config_and_hasher(TableConfig::default(), hasher)
+    }
+
+    pub fn with_config_and_hasher(config: TableConfig, hasher: S) -> Self {
+        assert!(config.load_factor > 0.0, "load factor must be positive");
+
         Self {
-            data: vec![Bucket::new()],
+            data: vec![Bucket::new(config.bucket_capacity)],
             current_level: 0,
             next: 0,
+            len: 0,
+            memory: 0,
+            measure: None,
+            clock: 0,
+            config,
+            hasher,
+        }
+    }
+
+    pub fn hasher(&self) -> &S {
+        &self.hasher
+    }
+
+    pub fn config(&self) -> TableConfig {
+        self.config
+    }
+
+    pub fn bucket_count(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn current_level(&self) -> usize {
+        self.current_level
+    }
+
+    // The next bucket to be split
+    pub fn next_split(&self) -> usize {
+        self.next
+    }
+
+    // The number of entries in each bucket, in bucket order
+    pub fn bucket_lens(&self) -> impl Iterator<Item = usize> + '_ {
+        self.data.iter().map(|bucket| bucket.entries.len())
+    }
+
+    pub fn load_factor(&self) -> f64 {
+        self.len as f64 / self.data.len() as f64
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // The approximate memory taken up by the table, including its buckets.
+    // Entries are only counted once `track_memory` has been called.
+    pub fn memory_usage(&self) -> usize {
+        size_of::<Self>() + self.data.len() * size_of::<Bucket<K, V>>() + self.memory
+    }
+
+    // The memory taken up by an entry, or 0 if memory usage isn't being tracked
+    fn measure(&self, key: &K, value: &V) -> usize {
+        self.measure.map_or(0, |measure| measure(key, value))
+    }
+
+    // Expired entries that haven't been removed yet are skipped.
+    pub fn entries(&self) -> impl Iterator<Item = &Entry<K, V>> {
+        let now = unix_time_ms();
+        self.data
+            .iter()
+            .flat_map(|bucket| bucket.entries.iter())
+            .filter(move |entry| !entry.is_expired(now))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries().map(|entry| (&entry.key, &entry.value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    // The reverse of split: folds the last bucket back into the bucket it was split from.
+    pub fn merge(&mut self) {
+        if self.data.len() <= 1 {
+            return;
         }
+
+        // Move the split pointer backwards, dropping down a level if we are at the start of a round
+        if self.next == 0 {
+            self.current_level -= 1;
+            self.next = 1 << self.current_level;
+        }
+        self.next -= 1;
+
+        // The last bucket is always the split image of the bucket at the (new) next index
+        let last = self.data.pop().unwrap();
+        self.data[self.next].entries.extend(last.entries);
     }
 
-    fn index(&self, key: &str) -> usize {
-        let index = hash(&key, self.current_level);
+    // Merges buckets until the table is no longer too sparse
+    fn shrink(&mut self) {
+        while self.data.len() > 1 && self.load_factor() < self.config.load_factor * MERGE_THRESHOLD {
+            self.merge();
+        }
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> Table<K, V, S> {
+    fn index<Q: Hash + ?Sized>(&self, key: &Q) -> usize {
+        self.index_of_hash(self.hasher.hash_one(key))
+    }
+
+    fn index_of_hash(&self, hash: u64) -> usize {
+        let index = bucket_at_level(hash, self.current_level);
 
         // If index less than next, then the bucket has been split this round, so we
         // take the higher level hash function to get the right bucket (which may be the same one).
         if index < self.next {
-            hash(&key, self.current_level + 1)
+            bucket_at_level(hash, self.current_level + 1)
         } else {
             index
         }
     }
 
-    pub fn set(&mut self, key: String, value: Value) {
+    fn remove_at(&mut self, index: usize, position: usize) -> Entry<K, V> {
+        let entry = self.data[index].entries.swap_remove(position);
+        self.len -= 1;
+        self.memory -= self.measure(&entry.key, &entry.value);
+        self.shrink();
+        entry
+    }
+
+    // Marks an entry as used, for eviction purposes
+    fn touch(&mut self, index: usize, position: usize) {
+        self.clock += 1;
+        self.data[index].entries[position].access.touch(self.clock);
+    }
+
+    // Active expiry: checks every entry in `samples` randomly chosen buckets and removes the
+    // expired ones. Returns how many of the checked entries had an expiry time, and how many of
+    // those were removed, so that callers can decide whether another round is worthwhile.
+    pub fn expire_sample(&mut self, samples: usize) -> (usize, usize) {
+        let now = unix_time_ms();
+        let mut checked = 0;
+        let mut removed = 0;
+        let mut freed = 0;
+        let measure = self.measure;
+
+        for _ in 0..samples {
+            let index = rand::random_range(0..self.data.len());
+            let bucket = &mut self.data[index];
+            checked += bucket.entries.iter().filter(|entry| entry.expires_at.is_some()).count();
+
+            bucket.entries.retain(|entry| {
+                if entry.is_expired(now) {
+                    removed += 1;
+                    freed += measure.map_or(0, |measure| measure(&entry.key, &entry.value));
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+
+        self.len -= removed;
+        self.memory -= freed;
+        self.shrink();
+        (checked, removed)
+    }
+
+    // Removes one entry according to the eviction policy and returns it, or None if there was
+    // nothing that could be evicted. Rather than keeping the entries in order, this looks at the entries
+    // in a few buckets and evicts the best candidate among them, like Redis does.
+    pub fn evict(&mut self, policy: EvictionPolicy) -> Option<Entry<K, V>> {
+        if policy == EvictionPolicy::NoEviction || self.is_empty() {
+            return None;
+        }
+
+        // The candidate with the lowest score is evicted
+        let clock = self.clock;
+        let score = |entry: &Entry<K, V>| -> Option<(u64, u64)> {
+            match policy {
+                EvictionPolicy::AllKeysLru => Some((entry.access.last_used, 0)),
+                EvictionPolicy::AllKeysLfu => Some((
+                    entry.access.decayed_frequency(clock) as u64,
+                    entry.access.last_used,
+                )),
+                EvictionPolicy::VolatileTtl => entry.expires_at.map(|expires_at| (expires_at, 0)),
+                EvictionPolicy::AllKeysRandom => Some((rand::random(), 0)),
+                EvictionPolicy::NoEviction => None,
+            }
+        };
+
+        // Keys are spread over the buckets by their hash, so the buckets following a random one are as good
+        // a sample as any. Buckets without a candidate don't count towards the sample, since under volatile-ttl
+        // most of them may have none, so this only gives up once it has been through every bucket.
+        let mut best: Option<((u64, u64), usize, usize)> = None;
+        let mut sampled = 0;
+        let start = rand::random_range(0..self.data.len());
+        for offset in 0..self.data.len() {
+            let index = (start + offset) % self.data.len();
+            let mut found = false;
+            for (position, entry) in self.data[index].entries.iter().enumerate() {
+                if let Some(score) = score(entry) {
+                    found = true;
+                    if best.is_none_or(|(best_score, _, _)| score < best_score) {
+                        best = Some((score, index, position));
+                    }
+                }
+            }
+
+            if found {
+                sampled += 1;
+                if sampled == EVICTION_SAMPLES {
+                    break;
+                }
+            }
+        }
+
+        let (_, index, position) = best?;
+        Some(self.remove_at(index, position))
+    }
+
+    // Finds the bucket and position of a key. Expired entries are removed on sight.
+    fn find<Q>(&mut self, key: &Q) -> Option<(usize, usize)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let index = self.index(key);
+        let position = self.data[index]
+            .entries
+            .iter()
+            .position(|entry| entry.key.borrow() == key)?;
+
+        if self.data[index].entries[position].is_expired(unix_time_ms()) {
+            self.remove_at(index, position);
+            return None;
+        }
+        Some((index, position))
+    }
+
+    // Sets the value of a key, clearing any expiry time it had.
+    pub fn set(&mut self, key: K, value: V) {
+        self.set_with_expiry(key, value, None);
+    }
+
+    pub fn set_with_expiry(&mut self, key: K, value: V, expires_at: Option<u64>) {
         let index = self.index(&key);
 
         // First check if entry already exists, and modify it if so.
-        for entry in self.data[index].entries.iter_mut() {
-            if entry.key == key {
-                entry.value = value;
-                return;
-            }
+        if let Some(position) = self.data[index].entries.iter().position(|entry| entry.key == key) {
+            let before = self.measure(&key, &self.data[index].entries[position].value);
+            self.memory = self.memory - before + self.measure(&key, &value);
+            let entry = &mut self.data[index].entries[position];
+            entry.value = value;
+            entry.expires_at = expires_at;
+            self.touch(index, position);
+            return;
         }
 
         // Otherwise, add the entry to the bucket.
-        self.data[index].entries.push(Entry { key, value });
+        self.clock += 1;
+        let mut access = Access::default();
+        access.touch(self.clock);
+
+        self.memory += self.measure(&key, &value);
+        self.data[index].entries.push(Entry {
+            key,
+            value,
+            expires_at,
+            access,
+        });
+        self.len += 1;
 
-        // If the bucket is full, split the next bucket (not necessarily this one)
-        if self.data[index].entries.len() > OVERFLOW_SIZE {
+        // If the table is over its target load, split the next bucket (not necessarily this one).
+        // Load factors below 1 may need more than one split per insert to catch up.
+        while self.load_factor() > self.config.load_factor {
             self.split();
         }
     }
 
+    // Checks the table's invariants, returning a description of each problem found. Meant for
+    // inspecting snapshots, since a table that has only been changed through its methods can't break them.
+    pub fn verify(&self) -> Vec<String>
+    where
+        K: fmt::Debug,
+    {
+        let mut problems = Vec::new();
+
+        let expected_buckets = (1 << self.current_level) + self.next;
+        if self.data.len() != expected_buckets || self.next >= 1 << self.current_level {
+            problems.push(format!(
+                "{} buckets, but level {} with next {} means there should be {}",
+                self.data.len(),
+                self.current_level,
+                self.next,
+                expected_buckets
+            ));
+        }
+
+        let mut len = 0;
+        for (index, bucket) in self.data.iter().enumerate() {
+            for entry in bucket.entries.iter() {
+                len += 1;
+
+                let expected = self.index(&entry.key);
+                if expected != index {
+                    problems.push(format!(
+                        "key {:?} is in bucket {}, but belongs in bucket {}",
+                        entry.key, index, expected
+                    ));
+                }
+            }
+        }
+
+        // The memory usage isn't checked, since it depends on how much spare capacity each string had
+        // when it was inserted, which doesn't survive a round trip through a snapshot
+        if len != self.len {
+            problems.push(format!("{} entries, but the recorded length is {}", len, self.len));
+        }
+        problems
+    }
+
     pub fn split(&mut self) {
         // Split the bucket at the next index
-        self.data.push(Bucket::new());
+        self.data.push(Bucket::new(self.config.bucket_capacity));
 
         // Rehash entries from bucket being split
         let old_entries = self.data[self.next].entries.drain(..).collect::<Vec<_>>();
         for entry in old_entries {
-            let index = hash(&entry.key, self.current_level + 1);
+            let index = bucket_at_level(self.hasher.hash_one(&entry.key), self.current_level + 1);
             self.data[index].entries.push(entry);
         }
 
@@ -120,26 +648,248 @@ impl Table {
         }
     }
 
-    pub fn get(&self, key: &str) -> Option<Value> {
-        let index = self.index(&key);
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (index, position) = self.find(key)?;
+        Some(self.remove_at(index, position).value)
+    }
+
+    // Takes `&mut self` because expired entries are removed when they are looked up.
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_entry(key).map(|entry| &entry.value)
+    }
+
+    // Modifies a value in place. Unlike `set`, this keeps its expiry time.
+    // Takes a closure rather than handing out a `&mut V` so that the memory usage can be updated afterwards.
+    pub fn update<Q, R>(&mut self, key: &Q, f: impl FnOnce(&mut V) -> R) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (index, position) = self.find(key)?;
+        self.touch(index, position);
+
+        let measure = self.measure;
+        let entry = &mut self.data[index].entries[position];
+        let before = measure.map_or(0, |measure| measure(&entry.key, &entry.value));
+        let result = f(&mut entry.value);
+        self.memory = self.memory - before + measure.map_or(0, |measure| measure(&entry.key, &entry.value));
+        Some(result)
+    }
+
+    pub fn get_entry<Q>(&mut self, key: &Q) -> Option<&Entry<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (index, position) = self.find(key)?;
+        self.touch(index, position);
+        Some(&self.data[index].entries[position])
+    }
+
+    // Sets when a key expires (in Unix milliseconds), or clears its expiry time if `None`.
+    // Returns false if the key doesn't exist.
+    pub fn set_expiry<Q>(&mut self, key: &Q, expires_at: Option<u64>) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let Some((index, position)) = self.find(key) else {
+            return false;
+        };
+        self.data[index].entries[position].expires_at = expires_at;
+        true
+    }
+
+    // Incrementally iterates over the table, Redis style. Each call visits at least one hash class
+    // and keeps going until it has found `count` entries or has wrapped around, then returns the
+    // cursor to pass to the next call (0 once the scan is complete). Every key that is present for
+    // the whole scan is returned at least once, even if buckets are split or merged in between.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<&Entry<K, V>>) {
+        // A hash class is the set of hashes that agree on the bits used at the next level,
+        // which is at most one bucket's worth of keys no matter how far the current round has got.
+        let mask = (1u64 << (self.current_level + 1)) - 1;
+
+        let now = unix_time_ms();
+        let mut cursor = cursor;
+        let mut entries = Vec::new();
+        loop {
+            let class = cursor & mask;
+            let bucket = &self.data[self.index_of_hash(class)];
+            entries.extend(bucket.entries.iter().filter(|entry| {
+                !entry.is_expired(now) && self.hasher.hash_one(&entry.key) & mask == class
+            }));
 
-        for entry in self.data[index].entries.iter() {
-            if entry.key == key {
-                return Some(entry.value.clone());
+            cursor = next_cursor(cursor, mask);
+            if cursor == 0 || entries.len() >= count {
+                return (cursor, entries);
             }
         }
-        None
     }
+}
 
-    pub fn to_disk(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let mut file = File::create(path)?;
-        bincode::serialize_into(&mut file, &self)?;
-        Ok(())
+// Working out the memory taken up by keys and values needs `MemoryUsage`, so only tables of types
+// that implement it can keep track of their memory usage
+impl<K: MemoryUsage, V: MemoryUsage, S> Table<K, V, S> {
+    // Starts counting the memory taken up by entries in `memory_usage`, beginning with the ones already there
+    pub fn track_memory(&mut self) {
+        self.measure = Some(entry_size::<K, V>);
+        self.memory = self
+            .data
+            .iter()
+            .flat_map(|bucket| bucket.entries.iter())
+            .map(|entry| entry_size(&entry.key, &entry.value))
+            .sum();
+    }
+}
+
+impl<K, V, S> Table<K, V, S> {
+    // Saves the table to a snapshot file (see `snapshot` for the format)
+    pub fn to_disk(&self, path: &str) -> Result<(), SnapshotError>
+    where
+        Self: Serialize,
+    {
+        self.to_disk_compressed(path, Compression::None)
+    }
+
+    pub fn to_disk_compressed(&self, path: &str, compression: Compression) -> Result<(), SnapshotError>
+    where
+        Self: Serialize,
+    {
+        write_snapshot(Path::new(path), self, compression)
     }
 
-    pub fn from_disk(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut file = File::open(path)?;
-        let table = bincode::deserialize_from(&mut file)?;
+    // Reads as many entries as possible from the start of a serialized table, for salvaging a damaged
+    // snapshot. Returns the entries along with the error that stopped it, if any.
+    pub fn recover_entries(payload: &[u8]) -> (Vec<Entry<K, V>>, Option<String>)
+    where
+        K: DeserializeOwned,
+        V: DeserializeOwned,
+    {
+        read_entries(payload)
+    }
+
+    // Loads a snapshot, whichever way it was compressed
+    pub fn from_disk(path: &str) -> Result<Self, SnapshotError>
+    where
+        Self: DeserializeOwned,
+    {
+        let (_, table) = read_snapshot(Path::new(path))?;
         Ok(table)
     }
 }
+
+// Reads entries of type `E` from the start of a serialized table, for as long as it can. This relies on
+// the buckets being the first field, so that the payload starts with the number of buckets, followed by
+// the number of entries in each bucket and then those entries (true of the legacy layout too).
+fn read_entries<E: DeserializeOwned>(payload: &[u8]) -> (Vec<E>, Option<String>) {
+    // The same encoding as `bincode::serialize`, but with a limit so that a damaged length can't
+    // make it try to allocate more memory than the whole payload takes up
+    let options = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(payload.len() as u64);
+
+    let mut reader = payload;
+    let mut entries = Vec::new();
+    let result: Result<(), bincode::Error> = (|| {
+        let buckets: u64 = options.deserialize_from(&mut reader)?;
+        for _ in 0..buckets {
+            let len: u64 = options.deserialize_from(&mut reader)?;
+            for _ in 0..len {
+                entries.push(options.deserialize_from(&mut reader)?);
+            }
+        }
+        Ok(())
+    })();
+    (entries, result.err().map(|e| e.to_string()))
+}
+
+// The layout of the table from before it was generic, which is what headerless snapshots hold.
+// Its keys were placed by an FNV hash of their bytes, so the entries are only good for rehashing.
+#[derive(Deserialize)]
+struct LegacyTable {
+    data: Vec<LegacyBucket>,
+    // Only there so that the whole payload is read
+    #[allow(dead_code)]
+    current_level: usize,
+    #[allow(dead_code)]
+    next: usize,
+}
+
+#[derive(Deserialize)]
+struct LegacyBucket {
+    entries: Vec<LegacyEntry>,
+}
+
+#[derive(Deserialize)]
+struct LegacyEntry {
+    key: String,
+    value: Value,
+}
+
+impl<S: BuildHasher> Table<String, Value, S> {
+    // Deserializes the payload of a snapshot of a keyspace. A headerless one may be in the layout from
+    // before tables were generic instead, in which case its entries are rehashed into a new table using `hasher`.
+    pub fn from_payload(payload: &[u8], headerless: bool, hasher: S) -> Result<Self, SnapshotError>
+    where
+        Self: DeserializeOwned,
+    {
+        let error = match bincode::deserialize(payload) {
+            Ok(table) => return Ok(table),
+            Err(e) => e,
+        };
+        if !headerless {
+            return Err(SnapshotError::Serialization(error));
+        }
+
+        let legacy: LegacyTable = bincode::deserialize(payload).map_err(SnapshotError::NotASnapshot)?;
+        let mut table = Self::with_hasher(hasher);
+        for entry in legacy.data.into_iter().flat_map(|bucket| bucket.entries) {
+            table.set(entry.key, entry.value);
+        }
+        Ok(table)
+    }
+
+    // Like `recover_entries`, but a headerless payload may also be in the layout from before tables were
+    // generic. Reading one of those as the current layout would misread every entry, so whichever layout
+    // gets through the payload (or failing that, furthest into it) is used.
+    pub fn recover_from_payload(payload: &[u8], headerless: bool) -> (Vec<Entry<String, Value>>, Option<String>) {
+        let current = Self::recover_entries(payload);
+        if !headerless || current.1.is_none() {
+            return current;
+        }
+
+        let (legacy, error) = read_entries::<LegacyEntry>(payload);
+        if error.is_some() && legacy.len() < current.0.len() {
+            return current;
+        }
+        let entries = legacy
+            .into_iter()
+            .map(|entry| Entry {
+                key: entry.key,
+                value: entry.value,
+                expires_at: None,
+                access: Access::default(),
+            })
+            .collect();
+        (entries, error)
+    }
+
+    // Like `from_disk`, but also reads snapshots of the keyspace from before tables were generic
+    pub fn load_keyspace(path: &Path, hasher: S) -> Result<(Option<Header>, Self), SnapshotError>
+    where
+        Self: DeserializeOwned,
+    {
+        let (header, payload) = read_payload(path)?;
+        let table = Self::from_payload(&payload, header.is_none(), hasher)?;
+        Ok((header, table))
+    }
+}
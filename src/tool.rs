@@ -0,0 +1,100 @@
+use clap::{Parser, Subcommand};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process;
+
+use rustdb::export::{export, import, Format};
+use rustdb::hash::SipBuildHasher;
+use rustdb::snapshot::Compression;
+use rustdb::table::Table;
+use rustdb::value::Value;
+
+#[derive(Parser)]
+#[command(name = "rustdb-tool")]
+#[command(version = "0.1.0")]
+#[command(about = "Converts rustdb snapshots to and from JSON Lines and CSV", long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: ToolCommand,
+}
+
+#[derive(Subcommand)]
+enum ToolCommand {
+    /// Write every key in a snapshot out as text
+    Export {
+        /// The snapshot file to read
+        #[arg(long)]
+        path: String,
+        /// jsonl or csv
+        #[arg(long, default_value = "jsonl")]
+        format: Format,
+        /// Where to write to (standard output by default)
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Read keys from text into a snapshot, creating it if it doesn't exist
+    Import {
+        /// The snapshot file to add the keys to
+        #[arg(long)]
+        path: String,
+        /// jsonl or csv
+        #[arg(long, default_value = "jsonl")]
+        format: Format,
+        /// Where to read from (standard input by default)
+        #[arg(long)]
+        input: Option<String>,
+        /// How to compress the snapshot: none, lz4 or zstd
+        #[arg(long, default_value = "none")]
+        compression: Compression,
+    },
+}
+
+// The same kind of table as the server's keyspace, so that the snapshots are interchangeable
+type Keyspace = Table<String, Value, SipBuildHasher>;
+
+// Snapshots from before tables were generic are rehashed with a new hasher
+fn load(path: &str) -> Result<Keyspace, String> {
+    let (_, table) = Keyspace::load_keyspace(Path::new(path), SipBuildHasher::random())
+        .map_err(|e| format!("failed to load {}: {}", path, e))?;
+    Ok(table)
+}
+
+fn run(command: ToolCommand) -> Result<(), String> {
+    match command {
+        ToolCommand::Export { path, format, output } => {
+            let table = load(&path)?;
+            let writer: Box<dyn Write> = match output {
+                Some(output) => Box::new(File::create(&output).map_err(|e| format!("{}: {}", output, e))?),
+                None => Box::new(io::stdout().lock()),
+            };
+            let count = export(&table, format, io::BufWriter::new(writer))?;
+            eprintln!("Exported {} keys", count);
+        }
+        ToolCommand::Import { path, format, input, compression } => {
+            let mut table = if Path::new(&path).exists() {
+                load(&path)?
+            } else {
+                Keyspace::with_hasher(SipBuildHasher::random())
+            };
+            let reader: Box<dyn Read> = match input {
+                Some(input) => Box::new(File::open(&input).map_err(|e| format!("{}: {}", input, e))?),
+                None => Box::new(io::stdin().lock()),
+            };
+            let count = import(&mut table, format, reader)?;
+            table
+                .to_disk_compressed(&path, compression)
+                .map_err(|e| format!("failed to save {}: {}", path, e))?;
+            eprintln!("Imported {} keys", count);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli.command) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
@@ -1,15 +1,19 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 use nom::branch::alt;
-use nom::bytes::complete::tag;
+use nom::bytes::complete::{tag, take_until};
 use nom::character::complete::{i64, u64};
-use nom::combinator::value;
+use nom::combinator::{map_res, value, verify};
+use nom::error::{Error, ErrorKind};
 use nom::multi::many_m_n;
 use nom::sequence::tuple;
 use nom::IResult;
 
-use crate::resp::RESP;
-use crate::resp::{parse_bulk, parse_simple};
+use crate::memory::MemoryUsage;
+use crate::resp::{
+    check_length, check_nesting, parse_bulk, parse_simple, Protocol, MAX_AGGREGATE_LEN, MAX_INLINE_LEN, RESP,
+};
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Value {
@@ -19,6 +23,45 @@ pub enum Value {
     Null,
     SimpleString(String),
     SimpleError(String),
+    // A bulk string that isn't valid UTF-8. Kept last so that older snapshots still decode.
+    Bytes(Vec<u8>),
+
+    // RESP3 types. RESP2 connections get the nearest RESP2 equivalent instead (see `encode`).
+    Map(Vec<(Value, Value)>),
+    Set(Vec<Value>),
+    Double(f64),
+    Boolean(bool),
+    // An integer too big for an i64, kept as its decimal digits
+    BigNumber(String),
+    // A string along with a three-letter format such as "txt" or "mkd"
+    VerbatimString(String, String),
+    // Out-of-band data sent by the server without being asked for, like pub/sub messages
+    Push(Vec<Value>),
+}
+
+// Escapes bytes the way redis-cli shows them: printable ASCII as-is, everything else as \xNN
+pub fn escape_bytes(bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+    for &byte in bytes {
+        match byte {
+            b'"' => escaped.push_str("\\\""),
+            b'\\' => escaped.push_str("\\\\"),
+            b'\n' => escaped.push_str("\\n"),
+            b'\r' => escaped.push_str("\\r"),
+            b'\t' => escaped.push_str("\\t"),
+            0x20..=0x7e => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    escaped
+}
+
+// A bulk string from the wire, which is only a `String` if it is valid UTF-8
+pub fn bulk_value(bytes: Vec<u8>) -> Value {
+    match String::from_utf8(bytes) {
+        Ok(s) => Value::String(s),
+        Err(e) => Value::Bytes(e.into_bytes()),
+    }
 }
 
 impl Value {
@@ -35,100 +78,370 @@ impl Value {
             ),
             Value::Null => "(nil)".to_string(),
             Value::SimpleString(s) => s.clone(),
-            Value::SimpleError(s) => format!("ERR: {}", s),
+            Value::SimpleError(s) => format!("(error) {}", s),
+            Value::Bytes(b) => format!("\"{}\"", escape_bytes(b)),
+            Value::Map(m) => format!(
+                "{{{}}}",
+                m.iter()
+                    .map(|(k, v)| format!("{}: {}", k.string_repr(), v.string_repr()))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Value::Set(s) => format!(
+                "(set) [{}]",
+                s.iter().map(|v| v.string_repr()).collect::<Vec<String>>().join(", ")
+            ),
+            Value::Double(d) => format!("(double) {}", format_double(*d)),
+            Value::Boolean(b) => format!("({})", b),
+            Value::BigNumber(n) => format!("(big number) {}", n),
+            Value::VerbatimString(_, text) => format!("\"{}\"", text),
+            Value::Push(p) => format!(
+                "(push) [{}]",
+                p.iter().map(|v| v.string_repr()).collect::<Vec<String>>().join(", ")
+            ),
+        }
+    }
+
+    // Encodes the value for a connection speaking the given protocol. RESP2 has no maps, sets, doubles
+    // and so on, so like Redis, those are sent as the closest RESP2 type: maps become flat arrays of keys
+    // and values, booleans become 1 or 0, and doubles, big numbers and verbatim strings become bulk strings.
+    pub fn encode(self, protocol: Protocol) -> Vec<u8> {
+        let resp3 = protocol == Protocol::Resp3;
+        match self {
+            Self::String(s) => bulk("$", s.as_bytes()),
+            Self::Bytes(b) => bulk("$", &b),
+            Self::Int(i) => format!(":{}\r\n", i).into_bytes(),
+            Self::Array(a) => aggregate("*", a.len(), a, protocol),
+            Self::Null if resp3 => b"_\r\n".to_vec(),
+            Self::Null => b"$-1\r\n".to_vec(),
+            Self::SimpleString(s) => simple("+", &s),
+            Self::SimpleError(s) => simple("-", &s),
+            Self::Map(m) => {
+                let len = m.len();
+                let contents = m.into_iter().flat_map(|(k, v)| [k, v]).collect();
+                if resp3 {
+                    aggregate("%", len, contents, protocol)
+                } else {
+                    aggregate("*", len * 2, contents, protocol)
+                }
+            }
+            Self::Set(s) => aggregate(if resp3 { "~" } else { "*" }, s.len(), s, protocol),
+            Self::Push(p) => aggregate(if resp3 { ">" } else { "*" }, p.len(), p, protocol),
+            Self::Double(d) if resp3 => format!(",{}\r\n", format_double(d)).into_bytes(),
+            Self::Double(d) => bulk("$", format_double(d).as_bytes()),
+            Self::Boolean(b) if resp3 => format!("#{}\r\n", if b { 't' } else { 'f' }).into_bytes(),
+            Self::Boolean(b) => format!(":{}\r\n", b as i64).into_bytes(),
+            Self::BigNumber(n) if resp3 => format!("({}\r\n", n).into_bytes(),
+            Self::BigNumber(n) => bulk("$", n.as_bytes()),
+            Self::VerbatimString(format, text) if resp3 => bulk("=", format!("{}:{}", format, text).as_bytes()),
+            Self::VerbatimString(_, text) => bulk("$", text.as_bytes()),
         }
     }
 }
 
-impl ToString for Value {
-    fn to_string(&self) -> String {
+// Doubles are written the way RESP3 spells them, including "inf", "-inf" and "nan"
+pub fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        "nan".to_string()
+    } else {
+        d.to_string()
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Value::SimpleString(s) => s.clone(),
-            Value::String(s) => s.clone(),
-            _ => panic!("Value is not a string"),
+            Value::SimpleString(s) => write!(f, "{}", s),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Bytes(b) => write!(f, "{}", String::from_utf8_lossy(b)),
+            _ => write!(f, "{}", self.string_repr()),
         }
     }
 }
 
-fn parse_null(input: &str) -> IResult<&str, Value> {
+fn parse_null(input: &[u8]) -> IResult<&[u8], Value> {
     value(Value::Null, tag("$-1\r\n"))(input)
 }
 
-fn parse_simple_string(input: &str) -> IResult<&str, Value> {
+fn parse_simple_string(input: &[u8]) -> IResult<&[u8], Value> {
     let (remaining, s) = parse_simple(input, "+")?;
     Ok((remaining, Value::SimpleString(s)))
 }
 
-fn parse_simple_error(input: &str) -> IResult<&str, Value> {
+fn parse_simple_error(input: &[u8]) -> IResult<&[u8], Value> {
     let (remaining, s) = parse_simple(input, "-")?;
     Ok((remaining, Value::SimpleError(s)))
 }
 
-fn parse_nonnull_string(input: &str) -> IResult<&str, Value> {
+fn parse_nonnull_string(input: &[u8]) -> IResult<&[u8], Value> {
     let (remaining, s) = parse_bulk(input, "$")?;
-    Ok((remaining, Value::String(s)))
+    Ok((remaining, bulk_value(s)))
 }
 
-fn parse_int(input: &str) -> IResult<&str, Value> {
+fn parse_int(input: &[u8]) -> IResult<&[u8], Value> {
     let (remaining, (_, i, _)) = tuple((tag(":"), i64, tag("\r\n")))(input)?;
 
     Ok((remaining, Value::Int(i)))
 }
 
-fn parse_array(input: &str) -> IResult<&str, Value> {
-    let (remaining, (_, len)) = tuple((tag("*"), u64))(input)?;
+// RESP2 has a null array as well as a null bulk string, but both are just nil to us
+fn parse_null_array(input: &[u8]) -> IResult<&[u8], Value> {
+    value(Value::Null, tag("*-1\r\n"))(input)
+}
 
-    let (remaining, (values, _)) = tuple((
-        many_m_n(len as usize, len as usize, parse_value),
-        tag("\r\n"),
-    ))(remaining)?;
+// The header of an aggregate type, giving the number of elements (or pairs, for a map)
+fn parse_length<'a>(input: &'a [u8], prefix: &'a str) -> IResult<&'a [u8], usize> {
+    let (remaining, (_, len, _)) = tuple((tag(prefix), u64, tag("\r\n")))(input)?;
+    check_length(input, len, MAX_AGGREGATE_LEN)?;
+    Ok((remaining, len as usize))
+}
+
+// The elements of an aggregate `depth` levels down
+fn parse_elements(input: &[u8], len: usize, depth: usize) -> IResult<&[u8], Vec<Value>> {
+    many_m_n(len, len, |input| parse_nested(input, depth + 1))(input)
+}
+
+fn parse_array(input: &[u8], depth: usize) -> IResult<&[u8], Value> {
+    let (remaining, len) = parse_length(input, "*")?;
+
+    let (remaining, values) = parse_elements(remaining, len, depth)?;
 
     Ok((remaining, Value::Array(values)))
 }
 
-pub fn parse_value(input: &str) -> IResult<&str, Value> {
-    alt((
-        parse_simple_string,
-        parse_simple_error,
-        parse_null,
-        parse_nonnull_string,
-        parse_int,
-        parse_array,
-    ))(input)
+fn parse_resp3_null(input: &[u8]) -> IResult<&[u8], Value> {
+    value(Value::Null, tuple((tag("_"), tag("\r\n"))))(input)
 }
 
-impl RESP for Value {
-    fn encode_resp(self) -> String {
-        match self {
-            Self::String(s) => {
-                format!("${}\r\n{}\r\n", s.len(), s)
-            }
-            Self::Int(i) => {
-                format!(":{}\r\n", i)
+fn parse_map(input: &[u8], depth: usize) -> IResult<&[u8], Value> {
+    let (remaining, len) = parse_length(input, "%")?;
+    let element = |input| parse_nested(input, depth + 1);
+    let (remaining, pairs) = many_m_n(len, len, tuple((element, element)))(remaining)?;
+    Ok((remaining, Value::Map(pairs)))
+}
+
+fn parse_set(input: &[u8], depth: usize) -> IResult<&[u8], Value> {
+    let (remaining, len) = parse_length(input, "~")?;
+    let (remaining, values) = parse_elements(remaining, len, depth)?;
+    Ok((remaining, Value::Set(values)))
+}
+
+fn parse_push(input: &[u8], depth: usize) -> IResult<&[u8], Value> {
+    let (remaining, len) = parse_length(input, ">")?;
+    let (remaining, values) = parse_elements(remaining, len, depth)?;
+    Ok((remaining, Value::Push(values)))
+}
+
+fn parse_double(input: &[u8]) -> IResult<&[u8], Value> {
+    // Rust's float parsing also takes care of "inf", "-inf" and "nan"
+    let (remaining, (_, d)) = tuple((tag(","), map_res(parse_line, str::parse::<f64>)))(input)?;
+    Ok((remaining, Value::Double(d)))
+}
+
+fn parse_boolean(input: &[u8]) -> IResult<&[u8], Value> {
+    let (remaining, (_, b, _)) = tuple((
+        tag("#"),
+        alt((value(true, tag("t")), value(false, tag("f")))),
+        tag("\r\n"),
+    ))(input)?;
+    Ok((remaining, Value::Boolean(b)))
+}
+
+// Whether a string is a valid big number: an optional sign, then at least one digit
+pub fn is_big_number(s: &str) -> bool {
+    let digits = s.strip_prefix(['-', '+']).unwrap_or(s);
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+// Splits the contents of a verbatim string, e.g. "txt:Some string", into its format and text
+pub fn verbatim_string(s: &str) -> Option<Value> {
+    match (s.get(..3), s.get(3..4), s.get(4..)) {
+        (Some(format), Some(":"), Some(text)) => Some(Value::VerbatimString(format.to_string(), text.to_string())),
+        _ => None,
+    }
+}
+
+fn parse_big_number(input: &[u8]) -> IResult<&[u8], Value> {
+    let (remaining, (_, n)) = tuple((tag("("), verify(parse_line, |s: &str| is_big_number(s))))(input)?;
+    Ok((remaining, Value::BigNumber(n.to_string())))
+}
+
+fn parse_verbatim_string(input: &[u8]) -> IResult<&[u8], Value> {
+    let split = |bytes: Vec<u8>| String::from_utf8(bytes).ok().and_then(|s| verbatim_string(&s)).ok_or(());
+    map_res(|input| parse_bulk(input, "="), split)(input)
+}
+
+// The rest of a line as text, for the simple types whose contents are written out in ASCII
+fn parse_line(input: &[u8]) -> IResult<&[u8], &str> {
+    let (remaining, (line, _)) = tuple((map_res(take_until("\r\n"), std::str::from_utf8), tag("\r\n")))(input)?;
+    Ok((remaining, line))
+}
+
+pub fn parse_value(input: &[u8]) -> IResult<&[u8], Value> {
+    parse_nested(input, 0)
+}
+
+// A value inside `depth` levels of aggregates
+fn parse_nested(input: &[u8], depth: usize) -> IResult<&[u8], Value> {
+    check_nesting(input, depth)?;
+
+    // Pick the parser from the type byte, so that an error comes from the parser that was meant
+    // rather than from whichever one happened to be tried last
+    match input.first() {
+        Some(b'+') => parse_simple_string(input),
+        Some(b'-') => parse_simple_error(input),
+        Some(b'$') if input.starts_with(b"$-") => parse_null(input),
+        Some(b'$') => parse_nonnull_string(input),
+        Some(b':') => parse_int(input),
+        Some(b'*') if input.starts_with(b"*-") => parse_null_array(input),
+        Some(b'*') => parse_array(input, depth),
+        Some(b'_') => parse_resp3_null(input),
+        Some(b'%') => parse_map(input, depth),
+        Some(b'~') => parse_set(input, depth),
+        Some(b'>') => parse_push(input, depth),
+        Some(b',') => parse_double(input),
+        Some(b'#') => parse_boolean(input),
+        Some(b'(') => parse_big_number(input),
+        Some(b'=') => parse_verbatim_string(input),
+        _ => Err(nom::Err::Error(Error::new(input, ErrorKind::Char))),
+    }
+}
+
+// An inline command, as typed into telnet: arguments separated by spaces on a single line.
+// This is what Redis falls back to for anything that doesn't start with '*'.
+// A line over MAX_INLINE_LEN fails with ErrorKind::TooLarge, and one with unbalanced quotes with ErrorKind::Escaped.
+pub fn parse_inline(input: &[u8]) -> IResult<&[u8], Value> {
+    let too_large = || nom::Err::Failure(Error::new(input, ErrorKind::TooLarge));
+    let (remaining, (line, _)) = match tuple((take_until("\n"), tag("\n")))(input) {
+        // Don't wait forever for the end of a line that is already too long
+        Err(nom::Err::Error(_)) if input.len() > MAX_INLINE_LEN => return Err(too_large()),
+        result => result?,
+    };
+    if line.len() > MAX_INLINE_LEN {
+        return Err(too_large());
+    }
+
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let Some(args) = split_inline_args(line) else {
+        return Err(nom::Err::Failure(Error::new(input, ErrorKind::Escaped)));
+    };
+    Ok((remaining, Value::Array(args.into_iter().map(bulk_value).collect())))
+}
+
+// Splits an inline command into arguments the way Redis's sdssplitargs does. Arguments are separated by
+// whitespace, and can be quoted: "double quotes" understand escapes like \n and \x41, while in
+// 'single quotes' only \' is special. Returns None if a quote isn't closed, or is closed in the middle
+// of an argument.
+fn split_inline_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let hex = |digit: u8| (digit as char).to_digit(16).unwrap() as u8;
+
+    let mut args = Vec::new();
+    let mut rest = line;
+    loop {
+        while let [byte, tail @ ..] = rest {
+            if !byte.is_ascii_whitespace() {
+                break;
             }
-            Self::Array(a) => {
-                let contents = a
-                    .iter()
-                    .map(|v| Self::encode_resp(v.clone()))
-                    .collect::<Vec<String>>()
-                    .join("");
-                format!("*{}{}\r\n", a.len(), contents)
+            rest = tail;
+        }
+        if rest.is_empty() {
+            return Some(args);
+        }
+
+        let mut arg = Vec::new();
+        let mut quote = None;
+        loop {
+            match (quote, rest) {
+                (Some(_), []) => return None,
+                (Some(quote), [byte, tail @ ..]) if *byte == quote => {
+                    if tail.first().is_some_and(|byte| !byte.is_ascii_whitespace()) {
+                        return None;
+                    }
+                    rest = tail;
+                    break;
+                }
+                (Some(b'"'), [b'\\', b'x', high, low, tail @ ..])
+                    if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() =>
+                {
+                    arg.push(hex(*high) << 4 | hex(*low));
+                    rest = tail;
+                }
+                (Some(b'"'), [b'\\', escaped, tail @ ..]) => {
+                    arg.push(match escaped {
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        b'b' => 0x08,
+                        b'a' => 0x07,
+                        other => *other,
+                    });
+                    rest = tail;
+                }
+                (Some(b'\''), [b'\\', b'\'', tail @ ..]) => {
+                    arg.push(b'\'');
+                    rest = tail;
+                }
+                (None, []) => break,
+                (None, [byte, ..]) if byte.is_ascii_whitespace() => break,
+                (None, [byte @ (b'"' | b'\''), tail @ ..]) => {
+                    quote = Some(*byte);
+                    rest = tail;
+                }
+                (_, [byte, tail @ ..]) => {
+                    arg.push(*byte);
+                    rest = tail;
+                }
             }
-            Self::Null => "$-1\r\n".to_string(),
-            Self::SimpleString(s) => format!("+{}\r\n", s),
-            Self::SimpleError(s) => format!("-{}\r\n", s),
         }
+        args.push(arg);
+    }
+}
+
+impl RESP for Value {
+    fn encode_resp(self) -> Vec<u8> {
+        self.encode(Protocol::Resp2)
     }
 
-    fn decode_resp(s: String) -> Result<Self, String> {
-        match parse_value(s.as_str()) {
+    fn decode_resp(bytes: &[u8]) -> Result<Self, crate::Error> {
+        match parse_value(bytes) {
             Ok((remaining, value)) => {
                 if !remaining.is_empty() {
-                    return Err("expected end of string".to_string());
+                    return Err(crate::Error::Protocol("expected end of string".to_string()));
                 }
                 Ok(value)
             }
-            Err(e) => Err(e.to_string()),
+            Err(e) => Err(crate::Error::Protocol(
+                e.map_input(|input| String::from_utf8_lossy(input).into_owned()).to_string(),
+            )),
+        }
+    }
+}
+
+// Simple strings and errors end at the first line break, so any in the text are replaced with spaces.
+// Otherwise the rest of it would be read as another value, throwing off every reply after it.
+fn simple(prefix: &str, s: &str) -> Vec<u8> {
+    format!("{}{}\r\n", prefix, s.replace(['\r', '\n'], " ")).into_bytes()
+}
+
+fn bulk(prefix: &str, bytes: &[u8]) -> Vec<u8> {
+    [format!("{}{}\r\n", prefix, bytes.len()).as_bytes(), bytes, b"\r\n"].concat()
+}
+
+fn aggregate(prefix: &str, len: usize, values: Vec<Value>, protocol: Protocol) -> Vec<u8> {
+    let contents = values.into_iter().flat_map(|v| v.encode(protocol)).collect::<Vec<u8>>();
+    [format!("{}{}\r\n", prefix, len).as_bytes(), &contents].concat()
+}
+
+impl MemoryUsage for Value {
+    fn heap_size(&self) -> usize {
+        match self {
+            Value::String(s) | Value::SimpleString(s) | Value::SimpleError(s) => s.heap_size(),
+            Value::Bytes(b) => b.heap_size(),
+            Value::BigNumber(s) => s.heap_size(),
+            Value::VerbatimString(format, text) => format.heap_size() + text.heap_size(),
+            Value::Array(a) | Value::Set(a) | Value::Push(a) => a.heap_size(),
+            Value::Map(m) => m.heap_size(),
+            Value::Int(_) | Value::Null | Value::Double(_) | Value::Boolean(_) => 0,
         }
     }
 }
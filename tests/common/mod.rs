@@ -0,0 +1,41 @@
+// Runs the server binary for integration tests. Not every test uses every part of it.
+#![allow(dead_code)]
+
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+
+pub struct Server {
+    process: Child,
+    pub port: u16,
+    pub resp_port: u16,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+// Starts an in-memory server listening for both websocket and plain RESP connections
+pub fn start_server() -> Server {
+    start_server_with(&[])
+}
+
+// Starts a server with extra command line arguments, e.g. a database file
+pub fn start_server_with(args: &[&str]) -> Server {
+    let (port, resp_port) = (free_port(), free_port());
+    let process = Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(["--port", &port.to_string()])
+        .args(["--resp-port", &resp_port.to_string()])
+        .arg("--no-autosave")
+        .args(args)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    Server { process, port, resp_port }
+}
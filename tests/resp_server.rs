@@ -0,0 +1,321 @@
+// Talks to the server's plain RESP listener over a raw socket, the way redis-cli and Redis client
+// libraries do.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use common::{start_server, start_server_with, Server};
+use rustdb::value::escape_bytes;
+
+fn connect(server: &Server) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(("127.0.0.1", server.resp_port)) {
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            return stream;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("server didn't start listening on port {}", server.resp_port);
+}
+
+// Sends a request and checks that exactly the expected bytes come back
+fn assert_reply(stream: &mut TcpStream, request: &[u8], expected: &[u8]) {
+    stream.write_all(request).unwrap();
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert!(reply == expected, "expected \"{}\", got \"{}\"", escape_bytes(expected), escape_bytes(&reply));
+}
+
+// Reads one line of a reply, including its CRLF
+fn read_line(stream: &mut TcpStream) -> String {
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        line.push(byte[0]);
+    }
+    String::from_utf8(line).unwrap()
+}
+
+// Sends a request whose reply is an integer, and returns it
+fn request_int(stream: &mut TcpStream, request: &[u8]) -> i64 {
+    stream.write_all(request).unwrap();
+    let line = read_line(stream);
+    line.strip_prefix(':').unwrap().trim_end().parse().unwrap()
+}
+
+// Sends a request for a section of INFO, and returns its fields
+fn request_info(stream: &mut TcpStream, section: &str) -> Vec<(String, String)> {
+    stream.write_all(format!("INFO {}\r\n", section).as_bytes()).unwrap();
+    let len: usize = read_line(stream).strip_prefix('$').unwrap().trim_end().parse().unwrap();
+    let mut body = vec![0; len + 2];
+    stream.read_exact(&mut body).unwrap();
+    String::from_utf8(body)
+        .unwrap()
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+fn info_field(info: &[(String, String)], name: &str) -> String {
+    info.iter().find(|(field, _)| field == name).unwrap().1.clone()
+}
+
+#[test]
+fn test_ping() {
+    let server = start_server();
+    let mut stream = connect(&server);
+
+    assert_reply(&mut stream, b"PING\r\n", b"+PONG\r\n");
+    assert_reply(&mut stream, b"ping\n", b"+PONG\r\n");
+    assert_reply(&mut stream, b"*1\r\n$4\r\nPING\r\n", b"+PONG\r\n");
+    assert_reply(&mut stream, b"*2\r\n$4\r\nPING\r\n$5\r\nhello\r\n", b"$5\r\nhello\r\n");
+}
+
+#[test]
+fn test_set_and_get() {
+    let server = start_server();
+    let mut stream = connect(&server);
+
+    assert_reply(&mut stream, b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n", b"$-1\r\n");
+    assert_reply(&mut stream, b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n", b"+OK\r\n");
+    assert_reply(&mut stream, b"*2\r\n$3\r\nget\r\n$3\r\nfoo\r\n", b"$3\r\nbar\r\n");
+    assert_reply(&mut stream, b"SET n 10\r\nGET n\r\n", b"+OK\r\n$2\r\n10\r\n");
+    assert_reply(&mut stream, b"SET s \"hello world\"\r\nGET s\r\n", b"+OK\r\n$11\r\nhello world\r\n");
+    assert_reply(&mut stream, b"*2\r\n$3\r\nDEL\r\n$3\r\nfoo\r\n", b":1\r\n");
+
+    // Values are binary-safe
+    assert_reply(&mut stream, b"*3\r\n$3\r\nSET\r\n$3\r\nbin\r\n$4\r\n\x00\r\n\xff\r\n", b"+OK\r\n");
+    assert_reply(&mut stream, b"*2\r\n$3\r\nGET\r\n$3\r\nbin\r\n", b"$4\r\n\x00\r\n\xff\r\n");
+}
+
+#[test]
+fn test_split_and_pipelined_requests() {
+    let server = start_server();
+    let mut stream = connect(&server);
+
+    // A request split across several writes is answered once it is complete
+    for part in [b"*3\r\n$3\r\nS".as_slice(), b"ET\r\n$1\r\nk\r", b"\n$1\r\nv\r\n"] {
+        stream.write_all(part).unwrap();
+        stream.flush().unwrap();
+        thread::sleep(Duration::from_millis(50));
+    }
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply).unwrap();
+    assert!(&reply == b"+OK\r\n");
+
+    // Several requests in one write are answered in order
+    assert_reply(
+        &mut stream,
+        b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*1\r\n$4\r\nPING\r\n\r\n*2\r\n$3\r\nTTL\r\n$1\r\nk\r\n",
+        b"$1\r\nv\r\n+PONG\r\n:-1\r\n",
+    );
+}
+
+#[test]
+fn test_errors() {
+    let server = start_server();
+    let mut stream = connect(&server);
+
+    assert_reply(&mut stream, b"*1\r\n$6\r\nFOOBAR\r\n", b"-ERR unknown command 'FOOBAR'\r\n");
+    // Line breaks in what is quoted back can't split the reply in two
+    assert_reply(&mut stream, b"*1\r\n$12\r\nFOO\r\n+INJECT\r\nPING\r\n", b"-ERR unknown command 'FOO  +INJECT'\r\n+PONG\r\n");
+    assert_reply(&mut stream, b"*1\r\n$3\r\nGET\r\n", b"-ERR wrong number of arguments for 'get' command\r\n");
+    assert_reply(&mut stream, b"EXPIRE k soon\r\n", b"-ERR value is not an integer or out of range\r\n");
+    assert_reply(&mut stream, b"SET k v\r\nINC k\r\n", b"+OK\r\n-WRONGTYPE Operation against a key holding the wrong kind of value\r\n");
+    assert_reply(&mut stream, b"DEC missing\r\n", b"-ERR no such key\r\n");
+    assert_reply(&mut stream, b"HELP\r\n", b"-ERR unknown command 'HELP'\r\n");
+    assert_reply(&mut stream, b"*1\r\n$4\r\nPING\r\n", b"+PONG\r\n");
+
+    // After a malformed request the connection is closed, since the rest of the input can't be trusted
+    assert_reply(&mut stream, b"*x\r\n", b"-ERR Protocol error: invalid frame\r\n");
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    // So is one that declares a length too big to accept, without waiting for it to arrive
+    let mut stream = connect(&server);
+    assert_reply(&mut stream, b"*2\r\n$3\r\nGET\r\n$9999999999\r\n", b"-ERR Protocol error: length too large\r\n");
+
+    // Other connections aren't affected
+    let mut stream = connect(&server);
+    assert_reply(&mut stream, b"PING\r\n", b"+PONG\r\n");
+}
+
+#[test]
+fn test_hello() {
+    let server = start_server();
+    let mut stream = connect(&server);
+
+    // Connections start out on RESP2, where HELLO's map is sent as a flat array
+    let fields = |proto: &str| {
+        format!(
+            "$6\r\nserver\r\n$6\r\nrustdb\r\n$7\r\nversion\r\n$5\r\n{}\r\n$5\r\nproto\r\n:{}\r\n\
+             $4\r\nmode\r\n$10\r\nstandalone\r\n$4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n",
+            env!("CARGO_PKG_VERSION"),
+            proto
+        )
+    };
+    assert_reply(&mut stream, b"HELLO\r\n", format!("*12\r\n{}", fields("2")).as_bytes());
+    assert_reply(&mut stream, b"GET missing\r\n", b"$-1\r\n");
+
+    assert_reply(&mut stream, b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n", format!("%6\r\n{}", fields("3")).as_bytes());
+    assert_reply(&mut stream, b"GET missing\r\n", b"_\r\n");
+    assert_reply(&mut stream, b"INFO foo\r\n", b"=4\r\ntxt:\r\n");
+
+    assert_reply(&mut stream, b"HELLO 4\r\n", b"-NOPROTO unsupported protocol version\r\n");
+    assert_reply(&mut stream, b"HELLO 2\r\n", format!("*12\r\n{}", fields("2")).as_bytes());
+    assert_reply(&mut stream, b"INFO foo\r\n", b"$0\r\n\r\n");
+
+    // The protocol belongs to the connection
+    let mut other = connect(&server);
+    assert_reply(&mut stream, b"HELLO 3\r\n", format!("%6\r\n{}", fields("3")).as_bytes());
+    assert_reply(&mut other, b"GET missing\r\n", b"$-1\r\n");
+}
+
+#[test]
+fn test_malformed_commands() {
+    let server = start_server();
+    let mut stream = connect(&server);
+
+    // Bad arguments get an error reply on the same connection
+    assert_reply(&mut stream, b"*1\r\n$3\r\nGET\r\n\r\n", b"-ERR wrong number of arguments for 'get' command\r\n");
+    assert_reply(&mut stream, b"*2\r\n$3\r\nGET\r\n*0\r\n", b"-ERR arguments must be valid UTF-8 strings\r\n");
+    assert_reply(&mut stream, b"*2\r\n$3\r\nGET\r\n:5\r\n", b"$-1\r\n");
+    assert_reply(&mut stream, b"*1\r\n:5\r\n", b"-ERR Protocol error: expected array of strings\r\n");
+    assert_reply(
+        &mut stream,
+        b"*3\r\n$3\r\nSET\r\n$1\r\nn\r\n:9223372036854775807\r\nINC n\r\n",
+        b"+OK\r\n-ERR increment or decrement would overflow\r\n",
+    );
+    assert_reply(&mut stream, b"PING\r\n", b"+PONG\r\n");
+
+    // Deeply nested input is refused before it can overflow the stack
+    let nested = [b"*1\r\n".repeat(100_000), b":1\r\n".to_vec()].concat();
+    assert_reply(&mut stream, &nested, b"-ERR Protocol error: too deeply nested\r\n");
+
+    let mut stream = connect(&server);
+    assert_reply(&mut stream, b"PING\r\n", b"+PONG\r\n");
+}
+
+#[test]
+fn test_background_save() {
+    let path = std::env::temp_dir().join(format!("rustdb-test-bgsave-{}", std::process::id()));
+    let server = start_server_with(&["--path", path.to_str().unwrap()]);
+    let mut stream = connect(&server);
+
+    let info = request_info(&mut stream, "persistence");
+    assert!(info_field(&info, "rdb_bgsave_in_progress") == "0");
+    assert!(info_field(&info, "rdb_last_bgsave_status") == "ok");
+    let started = request_int(&mut stream, b"LASTSAVE\r\n");
+
+    // Enough data that the save is still being written when the next requests arrive
+    let value = "x".repeat(1 << 20);
+    for i in 0..32 {
+        let request = format!("*3\r\n$3\r\nSET\r\n$3\r\nk{:02}\r\n${}\r\n{}\r\n", i, value.len(), value);
+        assert_reply(&mut stream, request.as_bytes(), b"+OK\r\n");
+    }
+    // LASTSAVE is in seconds
+    thread::sleep(Duration::from_millis(1100));
+
+    assert_reply(
+        &mut stream,
+        b"BGSAVE\r\nBGSAVE\r\n",
+        b"+Background saving started\r\n-ERR Background save already in progress\r\n",
+    );
+    let info = request_info(&mut stream, "persistence");
+    assert!(info_field(&info, "rdb_bgsave_in_progress") == "1");
+
+    let mut finished = false;
+    for _ in 0..200 {
+        let info = request_info(&mut stream, "persistence");
+        if info_field(&info, "rdb_bgsave_in_progress") == "0" {
+            assert!(info_field(&info, "rdb_last_bgsave_status") == "ok");
+            assert!(info_field(&info, "rdb_changes_since_last_save") == "0");
+            finished = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(finished, "background save didn't finish");
+    assert!(request_int(&mut stream, b"LASTSAVE\r\n") > started);
+    drop(server);
+    std::fs::remove_file(&path).unwrap();
+
+    // A save that fails is reported as such, and doesn't count as the last save
+    let path = std::env::temp_dir().join("rustdb-test-missing-dir").join("db");
+    let server = start_server_with(&["--path", path.to_str().unwrap()]);
+    let mut stream = connect(&server);
+    let started = request_int(&mut stream, b"LASTSAVE\r\n");
+    assert_reply(&mut stream, b"BGSAVE\r\n", b"+Background saving started\r\n");
+    let mut failed = false;
+    for _ in 0..200 {
+        let info = request_info(&mut stream, "persistence");
+        if info_field(&info, "rdb_bgsave_in_progress") == "0" {
+            failed = info_field(&info, "rdb_last_bgsave_status") == "err";
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(failed, "background save didn't fail");
+    assert!(request_int(&mut stream, b"LASTSAVE\r\n") == started);
+}
+
+#[test]
+fn test_empty_append_only_file() {
+    use rustdb::hash::SipBuildHasher;
+    use rustdb::table::Table;
+    use rustdb::value::Value;
+
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("rustdb-test-empty-aof-{}.db", std::process::id()));
+    let aof = dir.join(format!("rustdb-test-empty-aof-{}.aof", std::process::id()));
+    let mut table = Table::with_hasher(SipBuildHasher::random());
+    table.set("k".to_string(), Value::String("v".to_string()));
+    table.to_disk(path.to_str().unwrap()).unwrap();
+    std::fs::File::create(&aof).unwrap();
+
+    // An append-only file with nothing in it doesn't take the place of the snapshot
+    let args = ["--path", path.to_str().unwrap(), "--aof", aof.to_str().unwrap()];
+    let server = start_server_with(&args);
+    let mut stream = connect(&server);
+    assert_reply(&mut stream, b"GET k\r\n", b"$1\r\nv\r\n");
+    drop(server);
+
+    // Instead it is started off with what was in the snapshot
+    std::fs::remove_file(&path).unwrap();
+    let server = start_server_with(&args);
+    let mut stream = connect(&server);
+    assert_reply(&mut stream, b"GET k\r\n", b"$1\r\nv\r\n");
+    drop(server);
+
+    std::fs::remove_file(&aof).unwrap();
+}
+
+#[test]
+fn test_truncated_append_only_file() {
+    let aof = std::env::temp_dir().join(format!("rustdb-test-truncated-aof-{}.aof", std::process::id()));
+    std::fs::write(&aof, b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*3\r\n$3\r\nSET\r\n$1\r\nz\r\n$2\r\nab").unwrap();
+
+    // The truncated command at the end is dropped, so writes made after it survive the next restart
+    let args = ["--aof", aof.to_str().unwrap()];
+    let server = start_server_with(&args);
+    let mut stream = connect(&server);
+    assert_reply(&mut stream, b"GET k\r\n", b"$1\r\nv\r\n");
+    assert_reply(&mut stream, b"SET q hello\r\n", b"+OK\r\n");
+    drop(server);
+
+    let server = start_server_with(&args);
+    let mut stream = connect(&server);
+    assert_reply(&mut stream, b"GET k\r\n", b"$1\r\nv\r\n");
+    assert_reply(&mut stream, b"GET q\r\n", b"$5\r\nhello\r\n");
+    assert_reply(&mut stream, b"GET z\r\n", b"$-1\r\n");
+    drop(server);
+
+    std::fs::remove_file(&aof).unwrap();
+}
@@ -0,0 +1,109 @@
+// Talks to the server's websocket listener the way the client does, checking that commands can be
+// pipelined: several in one message, or several messages without waiting for the replies in between.
+
+mod common;
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use http::Uri;
+use tokio::net::TcpStream;
+use tokio_websockets::{ClientBuilder, MaybeTlsStream, Message, WebSocketStream};
+
+use common::{start_server, Server};
+use rustdb::value::escape_bytes;
+
+type Connection = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn connect(server: &Server) -> Connection {
+    let uri = Uri::from_str(&format!("ws://127.0.0.1:{}", server.port)).unwrap();
+    for _ in 0..100 {
+        if let Ok((client, _)) = ClientBuilder::from_uri(uri.clone()).connect().await {
+            return client;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("server didn't start listening on port {}", server.port);
+}
+
+async fn receive(client: &mut Connection) -> Vec<u8> {
+    let msg = tokio::time::timeout(Duration::from_secs(5), client.next()).await.unwrap().unwrap().unwrap();
+    msg.as_payload().to_vec()
+}
+
+fn assert_bytes(actual: &[u8], expected: &[u8]) {
+    assert!(actual == expected, "expected \"{}\", got \"{}\"", escape_bytes(expected), escape_bytes(actual));
+}
+
+#[tokio::test]
+async fn test_several_commands_in_one_message() {
+    let server = start_server();
+    let mut client = connect(&server).await;
+
+    let request = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n*2\r\n$3\r\nGET\r\n$1\r\na\r\n*2\r\n$3\r\nDEL\r\n$1\r\na\r\n";
+    client.send(Message::binary(request.to_vec())).await.unwrap();
+    assert_bytes(&receive(&mut client).await, b"+OK\r\n$1\r\n1\r\n:1\r\n");
+}
+
+#[tokio::test]
+async fn test_messages_back_to_back() {
+    let server = start_server();
+    let mut client = connect(&server).await;
+
+    for i in 0..100 {
+        let request = format!("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n${}\r\n{}\r\n", i.to_string().len(), i);
+        client.feed(Message::text(request)).await.unwrap();
+    }
+    client.flush().await.unwrap();
+    for _ in 0..100 {
+        assert_bytes(&receive(&mut client).await, b"+OK\r\n");
+    }
+
+    client.send(Message::text("*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")).await.unwrap();
+    assert_bytes(&receive(&mut client).await, b"$2\r\n99\r\n");
+}
+
+#[tokio::test]
+async fn test_command_split_across_messages() {
+    let server = start_server();
+    let mut client = connect(&server).await;
+
+    // The first message only completes the PING, so only that is answered
+    client.send(Message::text("*1\r\n$4\r\nPING\r\n*2\r\n$4\r\nPI")).await.unwrap();
+    assert_bytes(&receive(&mut client).await, b"+PONG\r\n");
+    client.send(Message::text("NG\r\n$2\r\nhi\r\n")).await.unwrap();
+    assert_bytes(&receive(&mut client).await, b"$2\r\nhi\r\n");
+
+    // An unknown command is an error, but the rest are still answered
+    client.send(Message::text("*1\r\n$4\r\nNOPE\r\n*1\r\n$4\r\nPING\r\n")).await.unwrap();
+    assert_bytes(&receive(&mut client).await, b"-ERR unknown command 'NOPE'\r\n+PONG\r\n");
+
+    // Garbage gets an error, and then the connection is closed
+    client.send(Message::text("?\r\n")).await.unwrap();
+    assert_bytes(&receive(&mut client).await, b"-ERR Protocol error: invalid frame\r\n");
+    let next = tokio::time::timeout(Duration::from_secs(5), client.next()).await.unwrap();
+    assert!(!matches!(next, Some(Ok(msg)) if msg.is_text() || msg.is_binary()));
+}
+
+#[tokio::test]
+async fn test_failed_handshake() {
+    use tokio::io::AsyncWriteExt;
+
+    let server = start_server();
+    let mut client = connect(&server).await;
+
+    // A connection that isn't a websocket handshake at all only takes itself down
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    drop(stream);
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).await.unwrap();
+    stream.write_all(b"\x00\xff garbage\r\n\r\n").await.unwrap();
+    drop(stream);
+
+    client.send(Message::text("*1\r\n$4\r\nPING\r\n")).await.unwrap();
+    assert_bytes(&receive(&mut client).await, b"+PONG\r\n");
+    let mut other = connect(&server).await;
+    other.send(Message::text("*1\r\n$4\r\nPING\r\n")).await.unwrap();
+    assert_bytes(&receive(&mut other).await, b"+PONG\r\n");
+}